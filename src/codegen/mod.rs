@@ -1,26 +1,73 @@
 #![allow(dead_code)] //suppress warnings for unused codes
 
-use crate::vm::Instruction;
+use crate::vm::{CodeAddr, Instruction};
 use std::collections::HashMap;
 
 ///parses a sequence of tokens into an AST
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ASTNode {
     Return(Box<Expr>),
     If { condition: Box<Expr>, then_branch: Box<ASTNode>, else_branch: Option<Box<ASTNode>> },
-    While { condition: Box<Expr>, body: Box<ASTNode> },
+    While { condition: Box<Expr>, body: Box<ASTNode>, step: Option<Box<ASTNode>> }, //step runs after each pass, 'for' puts its third clause here
+    Break,
+    Continue,
     Sequence(Vec<ASTNode>),
-    Declaration(String, Box<Expr>),
+    Block(Vec<ASTNode>), //a '{ ... }' block, whose declarations end with it
+    Declaration(String, Box<Expr>, Type),
     Assignment(String, Box<Expr>),
+    ExprStmt(Box<Expr>), //an expression run for its side effects, like 'foo();'
     FunctionDef {
         name: String,
         params: Vec<String>,
         body: Box<ASTNode>,
     },
     Print(String),
+    Printf { fmt: String, args: Vec<Expr> }, //printf with %d arguments
+}
+///declared type of a local, which decides how it is loaded and stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Int,  //a full word, LOAD/STORE
+    Char, //a single byte, LC/SC, so stores keep only the low 8 bits
+    Ptr,  //an address, stored as a word like Int
 }
+
+///the locals visible inside a function, one map per open block, innermost last
+struct Scopes(Vec<HashMap<String, (isize, Type)>>);
+
+impl Scopes {
+    fn new() -> Self {
+        Scopes(vec![HashMap::new()])
+    }
+
+    fn push(&mut self) {
+        self.0.push(HashMap::new());
+    }
+
+    fn pop(&mut self) {
+        self.0.pop();
+    }
+
+    //adds a name to the innermost scope, returning what it replaced in that same scope
+    fn declare(&mut self, name: &str, slot: (isize, Type)) -> Option<(isize, Type)> {
+        self.0.last_mut().expect("no open scope").insert(name.to_string(), slot)
+    }
+
+    //finds the innermost declaration of name
+    fn lookup(&self, name: &str) -> Option<(isize, Type)> {
+        self.0.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+}
+
+//break/continue jumps inside the innermost loop, patched once its addresses are known
+#[derive(Default)]
+struct LoopJumps {
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
 ///expression types for the AST
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Number(i64),
     Variable(String),
@@ -30,161 +77,494 @@ pub enum Expr {
     Div(Box<Expr>, Box<Expr>),
     Mod(Box<Expr>, Box<Expr>),
     Equal(Box<Expr>, Box<Expr>),
+    NotEqual(Box<Expr>, Box<Expr>),
     Less(Box<Expr>, Box<Expr>),
+    LessEqual(Box<Expr>, Box<Expr>),
     Greater(Box<Expr>, Box<Expr>),
+    GreaterEqual(Box<Expr>, Box<Expr>),
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    BitXor(Box<Expr>, Box<Expr>),
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>), //short-circuit '&&'
+    Or(Box<Expr>, Box<Expr>),  //short-circuit '||'
+    Neg(Box<Expr>),    //unary '-'
+    Not(Box<Expr>),    //logical '!', 1 when the operand is 0 and 0 otherwise
+    BitNot(Box<Expr>), //bitwise '~'
     Call(String, Vec<Expr>),
     Var(String),
 }
 
 
+///summary of the string literals a compiled program prints
+#[derive(Debug, PartialEq)]
+pub struct LiteralStats {
+    pub unique: usize, //number of distinct literals
+    pub bytes: usize,  //total size of the distinct literals, without terminators
+}
+
+///counts the distinct string literals used by the program and their size
+pub fn literal_stats(program: &[Instruction]) -> LiteralStats {
+    let mut seen: Vec<&str> = Vec::new();
+    for instr in program {
+        if let Instruction::PrintfStr(s) | Instruction::PrintfFmt(s, _) = instr {
+            if !seen.contains(&s.as_str()) {
+                seen.push(s);
+            }
+        }
+    }
+    LiteralStats {
+        unique: seen.len(),
+        bytes: seen.iter().map(|s| s.len()).sum(),
+    }
+}
+
+///splits the program into per-function instruction counts using the entry
+///table from generate_program, each function runs up to the next one's entry
+pub fn function_sizes(program: &[Instruction], functions: &[(String, usize)]) -> Vec<(String, usize)> {
+    functions
+        .iter()
+        .enumerate()
+        .map(|(i, (name, start))| {
+            let end = functions.get(i + 1).map_or(program.len(), |(_, next)| *next);
+            (name.clone(), end - start)
+        })
+        .collect()
+}
+
+///how --ast prints the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AstFormat {
+    Debug, //Rust's pretty Debug output
+    Json,  //see ast_to_json
+}
+
+impl std::str::FromStr for AstFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(AstFormat::Debug),
+            "json" => Ok(AstFormat::Json),
+            other => Err(format!("unknown AST format '{}' (expected debug or json)", other)),
+        }
+    }
+}
+
+///serializes the AST to JSON, one object per node keyed by the variant name
+///e.g. return 2 + 3; becomes {"Return":{"Add":[{"Number":2},{"Number":3}]}}
+pub fn ast_to_json(ast: &ASTNode) -> String {
+    match ast {
+        ASTNode::Return(expr) => format!("{{\"Return\":{}}}", expr_to_json(expr)),
+        ASTNode::If { condition, then_branch, else_branch } => format!(
+            "{{\"If\":{{\"condition\":{},\"then_branch\":{},\"else_branch\":{}}}}}",
+            expr_to_json(condition),
+            ast_to_json(then_branch),
+            else_branch.as_ref().map_or("null".to_string(), |e| ast_to_json(e))
+        ),
+        ASTNode::While { condition, body, step } => format!(
+            "{{\"While\":{{\"condition\":{},\"body\":{},\"step\":{}}}}}",
+            expr_to_json(condition),
+            ast_to_json(body),
+            step.as_ref().map_or("null".to_string(), |s| ast_to_json(s))
+        ),
+        ASTNode::Break => "\"Break\"".to_string(),
+        ASTNode::Continue => "\"Continue\"".to_string(),
+        ASTNode::Sequence(stmts) => {
+            let items: Vec<String> = stmts.iter().map(ast_to_json).collect();
+            format!("{{\"Sequence\":[{}]}}", items.join(","))
+        }
+        ASTNode::Block(stmts) => {
+            let items: Vec<String> = stmts.iter().map(ast_to_json).collect();
+            format!("{{\"Block\":[{}]}}", items.join(","))
+        }
+        ASTNode::Declaration(name, expr, ty) => format!(
+            "{{\"Declaration\":[{},{},\"{:?}\"]}}",
+            json_string(name),
+            expr_to_json(expr),
+            ty
+        ),
+        ASTNode::Assignment(name, expr) => {
+            format!("{{\"Assignment\":[{},{}]}}", json_string(name), expr_to_json(expr))
+        }
+        ASTNode::ExprStmt(expr) => format!("{{\"ExprStmt\":{}}}", expr_to_json(expr)),
+        ASTNode::FunctionDef { name, params, body } => {
+            let params: Vec<String> = params.iter().map(|p| json_string(p)).collect();
+            format!(
+                "{{\"FunctionDef\":{{\"name\":{},\"params\":[{}],\"body\":{}}}}}",
+                json_string(name),
+                params.join(","),
+                ast_to_json(body)
+            )
+        }
+        ASTNode::Print(s) => format!("{{\"Print\":{}}}", json_string(s)),
+        ASTNode::Printf { fmt, args } => {
+            let args: Vec<String> = args.iter().map(expr_to_json).collect();
+            format!("{{\"Printf\":{{\"fmt\":{},\"args\":[{}]}}}}", json_string(fmt), args.join(","))
+        }
+    }
+}
+
+///serializes an expression the same way as ast_to_json
+pub fn expr_to_json(expr: &Expr) -> String {
+    let (tag, lhs, rhs) = match expr {
+        Expr::Number(n) => return format!("{{\"Number\":{}}}", n),
+        Expr::Variable(name) => return format!("{{\"Variable\":{}}}", json_string(name)),
+        Expr::Var(name) => return format!("{{\"Var\":{}}}", json_string(name)),
+        Expr::Call(name, args) => {
+            let args: Vec<String> = args.iter().map(expr_to_json).collect();
+            return format!("{{\"Call\":[{},[{}]]}}", json_string(name), args.join(","));
+        }
+        Expr::Neg(e) => return format!("{{\"Neg\":{}}}", expr_to_json(e)),
+        Expr::Not(e) => return format!("{{\"Not\":{}}}", expr_to_json(e)),
+        Expr::BitNot(e) => return format!("{{\"BitNot\":{}}}", expr_to_json(e)),
+        Expr::Add(l, r) => ("Add", l, r),
+        Expr::Sub(l, r) => ("Sub", l, r),
+        Expr::Mul(l, r) => ("Mul", l, r),
+        Expr::Div(l, r) => ("Div", l, r),
+        Expr::Mod(l, r) => ("Mod", l, r),
+        Expr::Equal(l, r) => ("Equal", l, r),
+        Expr::NotEqual(l, r) => ("NotEqual", l, r),
+        Expr::Less(l, r) => ("Less", l, r),
+        Expr::LessEqual(l, r) => ("LessEqual", l, r),
+        Expr::Greater(l, r) => ("Greater", l, r),
+        Expr::GreaterEqual(l, r) => ("GreaterEqual", l, r),
+        Expr::BitAnd(l, r) => ("BitAnd", l, r),
+        Expr::BitOr(l, r) => ("BitOr", l, r),
+        Expr::BitXor(l, r) => ("BitXor", l, r),
+        Expr::Shl(l, r) => ("Shl", l, r),
+        Expr::Shr(l, r) => ("Shr", l, r),
+        Expr::And(l, r) => ("And", l, r),
+        Expr::Or(l, r) => ("Or", l, r),
+    };
+    format!("{{\"{}\":[{},{}]}}", tag, expr_to_json(lhs), expr_to_json(rhs))
+}
+
+//quotes a string for JSON, escaping quotes, backslashes and control characters
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
 ///generate VM instructions from parsed AST
 pub fn generate_instructions(ast: &ASTNode) -> Vec<Instruction> {
-    if let ASTNode::Sequence(nodes) = ast {
-        if nodes.iter().all(|n| matches!(n, ASTNode::FunctionDef { .. })) {
-            return vec![
-                Instruction::IMM(0),
-                Instruction::EXIT,
-            ];
+    generate_program(ast).0
+}
+
+///generates the instructions along with each function's name and entry pc,
+///sorted by entry pc, so the VM can name the frames in a backtrace
+///the top-level statements are listed as "main" at pc 0
+pub fn generate_program(ast: &ASTNode) -> (Vec<Instruction>, Vec<(String, usize)>) {
+    //top-level function definitions are emitted after the main body
+    let (mut functions, mut main_body): (Vec<&ASTNode>, Vec<&ASTNode>) = match ast {
+        ASTNode::Sequence(nodes) => nodes
+            .iter()
+            .partition(|n| matches!(n, ASTNode::FunctionDef { .. })),
+        other => (Vec::new(), vec![other]),
+    };
+    //main's body is the entry point, so it goes first in place of top-level statements
+    //without a main the entry just exits with 0, but the other functions are still compiled
+    if let Some(pos) = functions
+        .iter()
+        .position(|f| matches!(f, ASTNode::FunctionDef { name, .. } if name == "main"))
+    {
+        if let ASTNode::FunctionDef { body, .. } = functions.remove(pos) {
+            main_body.push(body);
         }
     }
+
     let mut instrs = Vec::new();
-    let mut symbol_table = HashMap::new();
+    let mut symbol_table = Scopes::new();
     let mut next_offset = 0;
     let mut patches: Vec<(usize, String)> = Vec::new();
 
     instrs.push(Instruction::ENT(0));
-    generate_instructions_inner(
-        ast,
-        &mut instrs,
-        &mut symbol_table,
-        &mut next_offset,
-        &mut patches,
-    );
+    for stmt in main_body {
+        generate_instructions_inner(
+            stmt,
+            &mut instrs,
+            &mut symbol_table,
+            &mut next_offset,
+            &mut patches,
+            &mut Vec::new(),
+            false,
+        );
+    }
     instrs[0] = Instruction::ENT(next_offset);
 
-    let function_addresses: HashMap<String, usize> = HashMap::new();
+    //falling off the end of main behaves like 'return 0;'
+    if can_reach_end(&instrs) {
+        instrs.push(Instruction::IMM(0));
+        instrs.push(Instruction::EXIT);
+    }
+
+    //each function starts right where its ENT is emitted
+    let mut function_addresses: HashMap<String, usize> = HashMap::new();
+    let mut entries = vec![("main".to_string(), 0)];
+    for func in functions {
+        if let ASTNode::FunctionDef { name, .. } = func {
+            function_addresses.insert(name.clone(), instrs.len());
+            entries.push((name.clone(), instrs.len()));
+        }
+        emit_function(func, &mut instrs, &mut patches);
+    }
+
+    let mut unresolved: Vec<String> = Vec::new();
     for (idx, name) in patches {
         if let Some(&addr) = function_addresses.get(&name) {
-            instrs[idx] = Instruction::JSR(addr);
-        } else {
-            panic!("Unresolved call to {}", name);
+            instrs[idx] = Instruction::JSR(CodeAddr(addr));
+        } else if name == "time" {
+            //builtin, used only when the program doesn't define its own time()
+            instrs[idx] = Instruction::TIME;
+        } else if !unresolved.contains(&name) {
+            unresolved.push(name);
         }
     }
+    //report every missing function at once instead of stopping at the first
+    if !unresolved.is_empty() {
+        panic!("unresolved functions: {}", unresolved.join(", "));
+    }
 
-    instrs
+    (instrs, entries)
 }
 
+//emits a function body wrapped in ENT/LEV
+//the caller pushes the arguments before JSR, so with n params the frame looks like
+//  arg0 .. arg(n-1), return address, saved bp | locals ...
+//and param i lives at bp - 2 - n + i
+fn emit_function(
+    func: &ASTNode,
+    instructions: &mut Vec<Instruction>,
+    patches: &mut Vec<(usize, String)>,
+) {
+    let ASTNode::FunctionDef { name, params, body } = func else {
+        return;
+    };
+    let mut symbol_table = Scopes::new();
+    let mut next_offset = 0;
+    let first_param = -2 - params.len() as isize;
+    for (i, param) in params.iter().enumerate() {
+        if symbol_table.declare(param, (first_param + i as isize, Type::Int)).is_some() {
+            panic!("duplicate parameter '{}' in function {}", param, name);
+        }
+    }
+
+    let ent_index = instructions.len();
+    instructions.push(Instruction::ENT(0));
+    generate_instructions_inner(body, instructions, &mut symbol_table, &mut next_offset, patches, &mut Vec::new(), true);
+    instructions[ent_index] = Instruction::ENT(next_offset);
 
+    //falling off the end of a function returns 0
+    if can_reach_end(instructions) {
+        instructions.push(Instruction::IMM(0));
+        instructions.push(Instruction::LEV);
+    }
+}
+
+//true if execution can run past the last instruction:
+//either it doesn't end in EXIT/LEV or some branch jumps just past the end
+pub(crate) fn can_reach_end(instrs: &[Instruction]) -> bool {
+    let end = instrs.len();
+    let jumps_to_end = instrs.iter().any(|i| match i {
+        Instruction::JMP(t) | Instruction::BZ(t) | Instruction::BNZ(t) => t.0 == end,
+        _ => false,
+    });
+    jumps_to_end || !matches!(instrs.last(), Some(Instruction::EXIT | Instruction::LEV))
+}
 
 ///recursively generates instructions from the AST
 fn generate_instructions_inner(
     ast: &ASTNode,
     instructions: &mut Vec<Instruction>,
-    symbol_table: &mut HashMap<String, usize>,
+    symbol_table: &mut Scopes,
     next_offset: &mut usize,
     patches: &mut Vec<(usize, String)>,
+    loops: &mut Vec<LoopJumps>,
+    in_function: bool,
 ) {
     match ast {
         ASTNode::Return(expr) => {
-             emit_expr(expr, instructions, symbol_table, patches);
-             //duplicate the return value so EXIT can see it
-             instructions.push(Instruction::PSH);
-             instructions.push(Instruction::EXIT);
-         }
+            emit_expr(expr, instructions, symbol_table, patches);
+            if in_function {
+                //LEV hands the value on top back to the caller
+                instructions.push(Instruction::LEV);
+            } else {
+                //the return value is on top, which is where EXIT reads it
+                instructions.push(Instruction::EXIT);
+            }
+        }
+        ASTNode::ExprStmt(expr) => {
+            //the value is not used, so drop it once computed
+            emit_expr(expr, instructions, symbol_table, patches);
+            instructions.push(Instruction::POP);
+        }
         ASTNode::Print(s) => {
             //push the literal onto the instruction stream
             instructions.push(Instruction::PrintfStr(s.clone()));
         }
+        ASTNode::Printf { fmt, args } => {
+            //arguments go on the stack in order, PrintfFmt pops them all
+            for arg in args {
+                emit_expr(arg, instructions, symbol_table, patches);
+            }
+            instructions.push(Instruction::PrintfFmt(fmt.clone(), args.len()));
+        }
 
         ASTNode::If { condition, then_branch, else_branch } => {
             //emit the condition expression
             emit_expr(condition, instructions, symbol_table, patches);
             let jump_false_index = instructions.len();
-            instructions.push(Instruction::BZ(9999));
+            instructions.push(Instruction::BZ(CodeAddr(9999)));
 
-            generate_instructions_inner(then_branch, instructions, symbol_table, next_offset, patches);
+            generate_instructions_inner(then_branch, instructions, symbol_table, next_offset, patches, loops, in_function);
 
             if let Some(else_branch) = else_branch {
                 let jump_over_else_index = instructions.len();
-                instructions.push(Instruction::JMP(9999));
+                instructions.push(Instruction::JMP(CodeAddr(9999)));
 
                 let else_start = instructions.len();
-                generate_instructions_inner(else_branch, instructions, symbol_table, next_offset, patches);
+                generate_instructions_inner(else_branch, instructions, symbol_table, next_offset, patches, loops, in_function);
 
                 let after_else = instructions.len();
-                instructions[jump_false_index] = Instruction::BZ(else_start);
-                instructions[jump_over_else_index] = Instruction::JMP(after_else);
+                instructions[jump_false_index] = Instruction::BZ(CodeAddr(else_start));
+                instructions[jump_over_else_index] = Instruction::JMP(CodeAddr(after_else));
             } else {
                 let after_then = instructions.len();
-                instructions[jump_false_index] = Instruction::BZ(after_then);
+                instructions[jump_false_index] = Instruction::BZ(CodeAddr(after_then));
             }
         }
         //emit the while loop
-        ASTNode::While { condition, body } => {
+        ASTNode::While { condition, body, step } => {
             let loop_start = instructions.len();
 
             emit_expr(condition, instructions, symbol_table, patches);
 
             let jump_if_false_index = instructions.len();
-            instructions.push(Instruction::BZ(9999));
+            instructions.push(Instruction::BZ(CodeAddr(9999)));
 
-            generate_instructions_inner(body, instructions, symbol_table, next_offset, patches);
+            loops.push(LoopJumps::default());
+            generate_instructions_inner(body, instructions, symbol_table, next_offset, patches, loops, in_function);
+            let jumps = loops.pop().expect("loop stack out of sync");
 
-            instructions.push(Instruction::JMP(loop_start));
+            //'continue' lands on the step, so a for loop still advances
+            let continue_target = CodeAddr(instructions.len());
+            if let Some(step) = step {
+                generate_instructions_inner(step, instructions, symbol_table, next_offset, patches, loops, in_function);
+            }
+            instructions.push(Instruction::JMP(CodeAddr(loop_start)));
 
             let loop_end = instructions.len();
-            instructions[jump_if_false_index] = Instruction::BZ(loop_end);
+            instructions[jump_if_false_index] = Instruction::BZ(CodeAddr(loop_end));
+            for idx in jumps.breaks {
+                instructions[idx] = Instruction::JMP(CodeAddr(loop_end));
+            }
+            for idx in jumps.continues {
+                instructions[idx] = Instruction::JMP(continue_target);
+            }
+        }
+        ASTNode::Break | ASTNode::Continue => {
+            let is_break = matches!(ast, ASTNode::Break);
+            let Some(jumps) = loops.last_mut() else {
+                panic!("'{}' outside of a loop", if is_break { "break" } else { "continue" });
+            };
+            let idx = instructions.len();
+            if is_break {
+                jumps.breaks.push(idx);
+            } else {
+                jumps.continues.push(idx);
+            }
+            instructions.push(Instruction::JMP(CodeAddr(9999))); //patched when the loop ends
         }
         //emit the sequence of statements
         ASTNode::Sequence(statements) => {
             for stmt in statements {
-                generate_instructions_inner(stmt, instructions, symbol_table, next_offset, patches);
+                generate_instructions_inner(stmt, instructions, symbol_table, next_offset, patches, loops, in_function);
+            }
+        }
+        //a block's names go out of scope at its end, but its slots stay reserved
+        //so every local in the function keeps its own frame offset
+        ASTNode::Block(statements) => {
+            symbol_table.push();
+            for stmt in statements {
+                generate_instructions_inner(stmt, instructions, symbol_table, next_offset, patches, loops, in_function);
             }
+            symbol_table.pop();
         }
         //emit the variable declaration
-        ASTNode::Declaration(name, expr) => {
-            let offset = *next_offset;
+        ASTNode::Declaration(name, expr, ty) => {
+            let offset = *next_offset as isize;
             *next_offset += 1;
-            symbol_table.insert(name.clone(), offset);
+            symbol_table.declare(name, (offset, *ty));
 
-            instructions.push(Instruction::LEA(offset));          
-            emit_expr(expr, instructions, symbol_table, patches);
-            instructions.push(Instruction::SI);
+            emit_store(offset, *ty, expr, instructions, symbol_table, patches);
         }
         //emit the assignment
         ASTNode::Assignment(name, expr) => {
-            if let Some(&offset) = symbol_table.get(name) {
-                instructions.push(Instruction::LEA(offset));      
-                emit_expr(expr, instructions, symbol_table, patches);
-                instructions.push(Instruction::SI);
+            if let Some((offset, ty)) = symbol_table.lookup(name) {
+                emit_store(offset, ty, expr, instructions, symbol_table, patches);
             } else {
                 panic!("Assignment to undeclared variable: {}", name);
             }
         }
-        //emit the function definition
-        ASTNode::FunctionDef { name: _, params, body } => {
-            symbol_table.clear();
-            *next_offset = params.len();
-            for (i, param) in params.iter().enumerate() {
-                symbol_table.insert(param.clone(), i);
-            }
-
-            generate_instructions_inner(body, instructions, symbol_table, next_offset, patches);
-
-
+        //functions are laid out by generate_instructions, never inline
+        ASTNode::FunctionDef { name, .. } => {
+            panic!("function {} must be defined at the top level", name);
         }
+    }
+}
 
 
-
+//evaluates expr and writes it into the frame slot, a byte at a time for char
+fn emit_store(
+    offset: isize,
+    ty: Type,
+    expr: &Expr,
+    instructions: &mut Vec<Instruction>,
+    symbol_table: &Scopes,
+    patches: &mut Vec<(usize, String)>,
+) {
+    match ty {
+        Type::Char => {
+            instructions.push(Instruction::LEA(offset));
+            emit_expr(expr, instructions, symbol_table, patches);
+            instructions.push(Instruction::SC);
+        }
+        Type::Int | Type::Ptr => {
+            emit_expr(expr, instructions, symbol_table, patches);
+            instructions.push(Instruction::STORE(offset)); //store straight into the frame slot
+        }
     }
 }
 
+//pushes the value in the frame slot, a single byte for char
+fn emit_load(offset: isize, ty: Type, instructions: &mut Vec<Instruction>) {
+    match ty {
+        Type::Char => {
+            instructions.push(Instruction::LEA(offset));
+            instructions.push(Instruction::LC);
+        }
+        Type::Int | Type::Ptr => instructions.push(Instruction::LOAD(offset)),
+    }
+}
 
 //emits instructions for a given expression
 fn emit_expr(
     expr: &Expr,
     instructions: &mut Vec<Instruction>,
-    symbol_table: &HashMap<String, usize>,
+    symbol_table: &Scopes,
     patches: &mut Vec<(usize, String)>,
 )
 {
@@ -193,6 +573,44 @@ fn emit_expr(
         Expr::Number(n) => { //push the number onto the stack 
             instructions.push(Instruction::IMM(*n));
         }
+        Expr::Neg(e) => {
+            emit_expr(e, instructions, symbol_table, patches);
+            instructions.push(Instruction::NEG);
+        }
+        Expr::Not(e) => {
+            //logical not is the same as comparing with 0
+            emit_expr(e, instructions, symbol_table, patches);
+            instructions.push(Instruction::IMM(0));
+            instructions.push(Instruction::EQ);
+        }
+        Expr::BitNot(e) => {
+            emit_expr(e, instructions, symbol_table, patches);
+            instructions.push(Instruction::BNOT);
+        }
+        //the right operand only runs when the left one doesn't decide the result:
+        //&& skips it once the left is 0, || once the left is non-zero
+        Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+            let is_and = matches!(expr, Expr::And(..));
+            let branch = |target| if is_and { Instruction::BZ(target) } else { Instruction::BNZ(target) };
+
+            emit_expr(lhs, instructions, symbol_table, patches);
+            let lhs_jump = instructions.len();
+            instructions.push(branch(CodeAddr(9999)));
+            emit_expr(rhs, instructions, symbol_table, patches);
+            let rhs_jump = instructions.len();
+            instructions.push(branch(CodeAddr(9999)));
+
+            //fell through both tests: 1 for &&, 0 for ||
+            instructions.push(Instruction::IMM(is_and as i64));
+            let jump_end = instructions.len();
+            instructions.push(Instruction::JMP(CodeAddr(9999)));
+
+            let short_circuit = CodeAddr(instructions.len());
+            instructions.push(Instruction::IMM(!is_and as i64));
+            instructions[lhs_jump] = branch(short_circuit);
+            instructions[rhs_jump] = branch(short_circuit);
+            instructions[jump_end] = Instruction::JMP(CodeAddr(instructions.len()));
+        }
         Expr::Add(lhs, rhs) => { 
             emit_expr(lhs, instructions, symbol_table, patches);
             emit_expr(rhs, instructions, symbol_table, patches);
@@ -233,10 +651,49 @@ fn emit_expr(
             emit_expr(rhs, instructions, symbol_table, patches);
             instructions.push(Instruction::GT);
         }
+        Expr::NotEqual(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::NE);
+        }
+        Expr::LessEqual(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::LE);
+        }
+        Expr::GreaterEqual(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::GE);
+        }
+        Expr::BitAnd(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::AND);
+        }
+        Expr::BitOr(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::OR);
+        }
+        Expr::BitXor(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::XOR);
+        }
+        Expr::Shl(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::SHL);
+        }
+        Expr::Shr(lhs, rhs) => {
+            emit_expr(lhs, instructions, symbol_table, patches);
+            emit_expr(rhs, instructions, symbol_table, patches);
+            instructions.push(Instruction::SHR);
+        }
         Expr::Variable(name) => { //load the variable value
-            if let Some(&offset) = symbol_table.get(name) {
-                instructions.push(Instruction::LEA(offset));
-                instructions.push(Instruction::LI); //load value from address
+            if let Some((offset, ty)) = symbol_table.lookup(name) {
+                emit_load(offset, ty, instructions); //load value from the frame slot
             } else {
                 panic!("Use of undeclared variable: {}", name);
             }
@@ -246,15 +703,18 @@ fn emit_expr(
                 emit_expr(arg, instructions, symbol_table, patches);
             }
             let placeholder_index = instructions.len();
-            instructions.push(Instruction::JSR(9999)); //temporary wrong address
+            instructions.push(Instruction::JSR(CodeAddr(9999))); //temporary wrong address
             patches.push((placeholder_index, func_name.clone())); // save for later patching
+            if !args.is_empty() {
+                //drop the arguments, keeping the return value on top
+                instructions.push(Instruction::ADJ(args.len()));
+            }
         }
 
         //load the variable value
         Expr::Var(name) => { 
-            if let Some(&offset) = symbol_table.get(name) {
-                instructions.push(Instruction::LEA(offset));
-                instructions.push(Instruction::LI);
+            if let Some((offset, ty)) = symbol_table.lookup(name) {
+                emit_load(offset, ty, instructions);
             } else {
                 panic!("Use of undeclared variable: {}", name);
             }
@@ -0,0 +1,464 @@
+use crate::codegen::{can_reach_end, ASTNode, Expr, Type};
+use crate::vm::{CodeAddr, Instruction};
+use std::collections::HashMap;
+use std::fmt;
+
+///binary operators available in the IR
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+}
+
+impl IrOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            IrOp::Add => "+",
+            IrOp::Sub => "-",
+            IrOp::Mul => "*",
+            IrOp::Div => "/",
+            IrOp::Mod => "%",
+            IrOp::Eq => "==",
+            IrOp::Ne => "!=",
+            IrOp::Lt => "<",
+            IrOp::Le => "<=",
+            IrOp::Gt => ">",
+            IrOp::Ge => ">=",
+            IrOp::And => "&",
+            IrOp::Or => "|",
+            IrOp::Xor => "^",
+            IrOp::Shl => "<<",
+            IrOp::Shr => ">>",
+        }
+    }
+
+    fn instruction(&self) -> Instruction {
+        match self {
+            IrOp::Add => Instruction::ADD,
+            IrOp::Sub => Instruction::SUB,
+            IrOp::Mul => Instruction::MUL,
+            IrOp::Div => Instruction::DIV,
+            IrOp::Mod => Instruction::MOD,
+            IrOp::Eq => Instruction::EQ,
+            IrOp::Ne => Instruction::NE,
+            IrOp::Lt => Instruction::LT,
+            IrOp::Le => Instruction::LE,
+            IrOp::Gt => Instruction::GT,
+            IrOp::Ge => Instruction::GE,
+            IrOp::And => Instruction::AND,
+            IrOp::Or => Instruction::OR,
+            IrOp::Xor => Instruction::XOR,
+            IrOp::Shl => Instruction::SHL,
+            IrOp::Shr => Instruction::SHR,
+        }
+    }
+}
+
+///unary operators available in the IR
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IrUnOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+impl IrUnOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            IrUnOp::Neg => "-",
+            IrUnOp::Not => "!",
+            IrUnOp::BitNot => "~",
+        }
+    }
+}
+
+///three-address instructions, temps are numbered t0, t1, ...
+#[derive(Debug, Clone, PartialEq)]
+pub enum IrInstr {
+    Const { dst: usize, value: i64 },
+    Load { dst: usize, name: String, slot: isize },
+    Store { name: String, slot: isize, src: usize, ty: Type },
+    BinOp { dst: usize, op: IrOp, lhs: usize, rhs: usize },
+    Unary { dst: usize, op: IrUnOp, src: usize },
+    Print(String),
+    Printf { fmt: String, args: Vec<usize> },
+    Label(usize),
+    Jump(usize),
+    JumpIfZero { cond: usize, label: usize },
+    Return(usize),
+}
+
+impl fmt::Display for IrInstr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrInstr::Const { dst, value } => write!(f, "t{} = {}", dst, value),
+            IrInstr::Load { dst, name, .. } => write!(f, "t{} = {}", dst, name),
+            IrInstr::Store { name, src, .. } => write!(f, "{} = t{}", name, src),
+            IrInstr::BinOp { dst, op, lhs, rhs } => {
+                write!(f, "t{} = t{} {} t{}", dst, lhs, op.symbol(), rhs)
+            }
+            IrInstr::Unary { dst, op, src } => write!(f, "t{} = {}t{}", dst, op.symbol(), src),
+            IrInstr::Print(s) => write!(f, "print {:?}", s),
+            IrInstr::Printf { fmt, args } => {
+                write!(f, "print {:?}", fmt)?;
+                for arg in args {
+                    write!(f, ", t{}", arg)?;
+                }
+                Ok(())
+            }
+            IrInstr::Label(l) => write!(f, "L{}:", l),
+            IrInstr::Jump(l) => write!(f, "goto L{}", l),
+            IrInstr::JumpIfZero { cond, label } => write!(f, "if t{} == 0 goto L{}", cond, label),
+            IrInstr::Return(src) => write!(f, "return t{}", src),
+        }
+    }
+}
+
+///an IR program: the instruction list plus how many locals and temps it uses
+#[derive(Debug, PartialEq)]
+pub struct IrProgram {
+    pub code: Vec<IrInstr>,
+    pub locals: usize,
+    pub temps: usize,
+}
+
+impl fmt::Display for IrProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for instr in &self.code {
+            match instr {
+                IrInstr::Label(_) => writeln!(f, "{}", instr)?,
+                _ => writeln!(f, "    {}", instr)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+///state used while flattening the AST into IR
+struct IrBuilder {
+    code: Vec<IrInstr>,
+    symbols: HashMap<String, (isize, Type)>,
+    locals: usize,
+    temps: usize,
+    labels: usize,
+    loops: Vec<(usize, usize)>, //(continue label, break label) of each enclosing loop
+    unsupported: Option<String>, //first construct the IR cannot express yet
+}
+
+///builds the three-address IR for a parsed program, panicking on anything it cannot express
+pub fn build_ir(ast: &ASTNode) -> IrProgram {
+    try_build_ir(ast).unwrap_or_else(|e| panic!("{}", e))
+}
+
+///builds the IR like build_ir, but hands unsupported constructs back as an error message
+pub fn try_build_ir(ast: &ASTNode) -> Result<IrProgram, String> {
+    let mut builder = IrBuilder {
+        code: Vec::new(),
+        symbols: HashMap::new(),
+        locals: 0,
+        temps: 0,
+        labels: 0,
+        loops: Vec::new(),
+        unsupported: None,
+    };
+    builder.stmt(ast);
+    match builder.unsupported {
+        Some(msg) => Err(msg),
+        None => Ok(IrProgram { code: builder.code, locals: builder.locals, temps: builder.temps }),
+    }
+}
+
+impl IrBuilder {
+    fn new_temp(&mut self) -> usize {
+        self.temps += 1;
+        self.temps - 1
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn unsupported(&mut self, msg: String) {
+        self.unsupported.get_or_insert(msg);
+    }
+
+    fn slot_of(&self, name: &str) -> isize {
+        match self.symbols.get(name) {
+            Some(&(slot, _)) => slot,
+            None => panic!("Use of undeclared variable: {}", name),
+        }
+    }
+
+    fn stmt(&mut self, ast: &ASTNode) {
+        match ast {
+            ASTNode::Sequence(stmts) => {
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+            }
+            //names declared in the block are forgotten after it, slots are never reused
+            ASTNode::Block(stmts) => {
+                let outer = self.symbols.clone();
+                for stmt in stmts {
+                    self.stmt(stmt);
+                }
+                self.symbols = outer;
+            }
+            ASTNode::Declaration(name, expr, ty) => {
+                let slot = self.locals as isize;
+                self.locals += 1;
+                self.symbols.insert(name.clone(), (slot, *ty));
+                let src = self.expr(expr);
+                self.code.push(IrInstr::Store { name: name.clone(), slot, src, ty: *ty });
+            }
+            ASTNode::Assignment(name, expr) => {
+                let (slot, ty) = match self.symbols.get(name) {
+                    Some(&entry) => entry,
+                    None => panic!("Assignment to undeclared variable: {}", name),
+                };
+                let src = self.expr(expr);
+                self.code.push(IrInstr::Store { name: name.clone(), slot, src, ty });
+            }
+            ASTNode::Return(expr) => {
+                let src = self.expr(expr);
+                self.code.push(IrInstr::Return(src));
+            }
+            ASTNode::ExprStmt(expr) => {
+                self.expr(expr); //the result temp is simply never read
+            }
+            ASTNode::Print(s) => self.code.push(IrInstr::Print(s.clone())),
+            ASTNode::Printf { fmt, args } => {
+                let args = args.iter().map(|a| self.expr(a)).collect();
+                self.code.push(IrInstr::Printf { fmt: fmt.clone(), args });
+            }
+            ASTNode::If { condition, then_branch, else_branch } => {
+                let cond = self.expr(condition);
+                let else_label = self.new_label();
+                self.code.push(IrInstr::JumpIfZero { cond, label: else_label });
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    let end_label = self.new_label();
+                    self.code.push(IrInstr::Jump(end_label));
+                    self.code.push(IrInstr::Label(else_label));
+                    self.stmt(else_branch);
+                    self.code.push(IrInstr::Label(end_label));
+                } else {
+                    self.code.push(IrInstr::Label(else_label));
+                }
+            }
+            ASTNode::While { condition, body, step } => {
+                let start_label = self.new_label();
+                let continue_label = self.new_label();
+                let end_label = self.new_label();
+                self.code.push(IrInstr::Label(start_label));
+                let cond = self.expr(condition);
+                self.code.push(IrInstr::JumpIfZero { cond, label: end_label });
+                self.loops.push((continue_label, end_label));
+                self.stmt(body);
+                self.loops.pop();
+                self.code.push(IrInstr::Label(continue_label));
+                if let Some(step) = step {
+                    self.stmt(step);
+                }
+                self.code.push(IrInstr::Jump(start_label));
+                self.code.push(IrInstr::Label(end_label));
+            }
+            ASTNode::Break | ASTNode::Continue => {
+                let Some(&(continue_label, break_label)) = self.loops.last() else {
+                    panic!("'{}' outside of a loop", if matches!(ast, ASTNode::Break) { "break" } else { "continue" });
+                };
+                let label = if matches!(ast, ASTNode::Break) { break_label } else { continue_label };
+                self.code.push(IrInstr::Jump(label));
+            }
+            //main's body is the program itself
+            ASTNode::FunctionDef { name, body, .. } if name == "main" => self.stmt(body),
+            ASTNode::FunctionDef { name, .. } => {
+                self.unsupported(format!("IR does not support function definitions yet: {}", name))
+            }
+        }
+    }
+
+    //emits the IR for an expression and returns the temp holding its value
+    fn expr(&mut self, expr: &Expr) -> usize {
+        let (op, lhs, rhs) = match expr {
+            Expr::Number(n) => {
+                let dst = self.new_temp();
+                self.code.push(IrInstr::Const { dst, value: *n });
+                return dst;
+            }
+            Expr::Variable(name) | Expr::Var(name) => {
+                let slot = self.slot_of(name);
+                let dst = self.new_temp();
+                self.code.push(IrInstr::Load { dst, name: name.clone(), slot });
+                return dst;
+            }
+            //the call is left out and stands in as 0, try_build_ir reports it
+            Expr::Call(name, _) => {
+                self.unsupported(format!("IR does not support calls yet: {}", name));
+                let dst = self.new_temp();
+                self.code.push(IrInstr::Const { dst, value: 0 });
+                return dst;
+            }
+            //dst starts as the answer when the left operand decides it, the right one
+            //is only evaluated when it does not
+            Expr::And(l, r) | Expr::Or(l, r) => {
+                let is_and = matches!(expr, Expr::And(..));
+                let dst = self.new_temp();
+                let end_label = self.new_label();
+                self.code.push(IrInstr::Const { dst, value: !is_and as i64 });
+                let lhs = self.expr(l);
+                if is_and {
+                    self.code.push(IrInstr::JumpIfZero { cond: lhs, label: end_label });
+                } else {
+                    let rhs_label = self.new_label();
+                    self.code.push(IrInstr::JumpIfZero { cond: lhs, label: rhs_label });
+                    self.code.push(IrInstr::Jump(end_label));
+                    self.code.push(IrInstr::Label(rhs_label));
+                    self.code.push(IrInstr::Const { dst, value: 0 });
+                }
+                let rhs = self.expr(r);
+                self.code.push(IrInstr::JumpIfZero { cond: rhs, label: end_label });
+                self.code.push(IrInstr::Const { dst, value: 1 });
+                self.code.push(IrInstr::Label(end_label));
+                return dst;
+            }
+            Expr::Neg(e) | Expr::Not(e) | Expr::BitNot(e) => {
+                let op = match expr {
+                    Expr::Neg(_) => IrUnOp::Neg,
+                    Expr::Not(_) => IrUnOp::Not,
+                    _ => IrUnOp::BitNot,
+                };
+                let src = self.expr(e);
+                let dst = self.new_temp();
+                self.code.push(IrInstr::Unary { dst, op, src });
+                return dst;
+            }
+            Expr::Add(l, r) => (IrOp::Add, l, r),
+            Expr::Sub(l, r) => (IrOp::Sub, l, r),
+            Expr::Mul(l, r) => (IrOp::Mul, l, r),
+            Expr::Div(l, r) => (IrOp::Div, l, r),
+            Expr::Mod(l, r) => (IrOp::Mod, l, r),
+            Expr::Equal(l, r) => (IrOp::Eq, l, r),
+            Expr::Less(l, r) => (IrOp::Lt, l, r),
+            Expr::Greater(l, r) => (IrOp::Gt, l, r),
+            Expr::NotEqual(l, r) => (IrOp::Ne, l, r),
+            Expr::LessEqual(l, r) => (IrOp::Le, l, r),
+            Expr::GreaterEqual(l, r) => (IrOp::Ge, l, r),
+            Expr::BitAnd(l, r) => (IrOp::And, l, r),
+            Expr::BitOr(l, r) => (IrOp::Or, l, r),
+            Expr::BitXor(l, r) => (IrOp::Xor, l, r),
+            Expr::Shl(l, r) => (IrOp::Shl, l, r),
+            Expr::Shr(l, r) => (IrOp::Shr, l, r),
+        };
+        let lhs = self.expr(lhs);
+        let rhs = self.expr(rhs);
+        let dst = self.new_temp();
+        self.code.push(IrInstr::BinOp { dst, op, lhs, rhs });
+        dst
+    }
+}
+
+///lowers the IR to VM instructions
+///locals keep their frame slots and every temp gets its own slot after them
+pub fn lower_ir(ir: &IrProgram) -> Vec<Instruction> {
+    let temp_slot = |t: &usize| (ir.locals + *t) as isize;
+    let mut instrs = vec![Instruction::ENT(ir.locals + ir.temps)];
+    let mut label_addrs: HashMap<usize, usize> = HashMap::new();
+    let mut patches: Vec<(usize, usize)> = Vec::new();
+
+    for instr in &ir.code {
+        match instr {
+            IrInstr::Const { dst, value } => {
+                instrs.push(Instruction::IMM(*value));
+                instrs.push(Instruction::STORE(temp_slot(dst)));
+            }
+            IrInstr::Load { dst, slot, .. } => {
+                instrs.push(Instruction::LOAD(*slot));
+                instrs.push(Instruction::STORE(temp_slot(dst)));
+            }
+            //char slots only ever hold a byte, so a plain LOAD reads them back correctly
+            IrInstr::Store { slot, src, ty: Type::Char, .. } => {
+                instrs.push(Instruction::LEA(*slot));
+                instrs.push(Instruction::LOAD(temp_slot(src)));
+                instrs.push(Instruction::SC);
+            }
+            IrInstr::Store { slot, src, .. } => {
+                instrs.push(Instruction::LOAD(temp_slot(src)));
+                instrs.push(Instruction::STORE(*slot));
+            }
+            IrInstr::BinOp { dst, op, lhs, rhs } => {
+                instrs.push(Instruction::LOAD(temp_slot(lhs)));
+                instrs.push(Instruction::LOAD(temp_slot(rhs)));
+                instrs.push(op.instruction());
+                instrs.push(Instruction::STORE(temp_slot(dst)));
+            }
+            IrInstr::Unary { dst, op, src } => {
+                instrs.push(Instruction::LOAD(temp_slot(src)));
+                match op {
+                    IrUnOp::Neg => instrs.push(Instruction::NEG),
+                    IrUnOp::Not => {
+                        instrs.push(Instruction::IMM(0));
+                        instrs.push(Instruction::EQ);
+                    }
+                    IrUnOp::BitNot => instrs.push(Instruction::BNOT),
+                }
+                instrs.push(Instruction::STORE(temp_slot(dst)));
+            }
+            IrInstr::Print(s) => instrs.push(Instruction::PrintfStr(s.clone())),
+            IrInstr::Printf { fmt, args } => {
+                for arg in args {
+                    instrs.push(Instruction::LOAD(temp_slot(arg)));
+                }
+                instrs.push(Instruction::PrintfFmt(fmt.clone(), args.len()));
+            }
+            IrInstr::Label(l) => {
+                label_addrs.insert(*l, instrs.len());
+            }
+            IrInstr::Jump(l) => {
+                patches.push((instrs.len(), *l));
+                instrs.push(Instruction::JMP(CodeAddr(9999)));
+            }
+            IrInstr::JumpIfZero { cond, label } => {
+                instrs.push(Instruction::LOAD(temp_slot(cond)));
+                patches.push((instrs.len(), *label));
+                instrs.push(Instruction::BZ(CodeAddr(9999)));
+            }
+            IrInstr::Return(src) => {
+                instrs.push(Instruction::LOAD(temp_slot(src)));
+                instrs.push(Instruction::EXIT);
+            }
+        }
+    }
+
+    //labels are only known once everything is emitted, so patch jumps last
+    for (idx, label) in patches {
+        let target = CodeAddr(label_addrs[&label]);
+        instrs[idx] = match instrs[idx] {
+            Instruction::BZ(_) => Instruction::BZ(target),
+            _ => Instruction::JMP(target),
+        };
+    }
+
+    //falling off the end behaves like 'return 0;', as in direct codegen
+    if can_reach_end(&instrs) {
+        instrs.push(Instruction::IMM(0));
+        instrs.push(Instruction::EXIT);
+    }
+
+    instrs
+}
@@ -1,9 +1,14 @@
 #![allow(dead_code)] //suppress warnings for unused opcodes
 
+use std::iter::Peekable;
+use std::str::Chars;
+
 ///tokens that are recognized by the lexer
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token { ///token types
     Int,
+    Char,
+    Void,
     Return,
     Identifier(String), 
     Number(i64),
@@ -18,25 +23,97 @@ pub enum Token { ///token types
     Divide,
     Mod,
     Equal,
+    NotEqual,
     Less,
+    LessEqual,
     Greater,
+    GreaterEqual,
+    Not,
+    And, //'&&'
+    Or,  //'||'
+    BitAnd, //single '&'
+    BitOr,  //single '|'
+    Tilde,
+    Caret,
+    Shl,
+    Shr,
     If,
     Else,
     While,
+    For,
+    Break,
+    Continue,
     Assign,
+    PlusPlus,   //'++'
+    MinusMinus, //'--'
+    PlusEq,     //'+='
+    MinusEq,    //'-='
+    StarEq,     //'*='
+    DivEq,      //'/='
+    ModEq,      //'%='
     Comma,
+    Arrow,
+    Dot,
     Div,
     StringLiteral(String),
+    CharLiteral(i64), //byte value of a 'c' constant
     Unknown(char),
 }
 
 
+///a token with the 1-based line and column where it starts
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub line: usize,
+    pub col: usize,
+}
+
+///character iterator that keeps track of the line and column of the next character
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    col: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+}
+
+impl Iterator for Cursor<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+}
+
+///tokenizes the source and drops the positions, for callers that only need the tokens
+pub fn tokens(source: &str) -> Vec<Token> {
+    tokenize(source).into_iter().map(|t| t.token).collect()
+}
+
 ///converts source code string into a vector of tokens, using match here
-pub fn tokenize(source: &str) -> Vec<Token> {
+///each token records the line and column it started at
+pub fn tokenize(source: &str) -> Vec<SpannedToken> {
     let mut tokens = Vec::new();
-    let mut chars = source.chars().peekable();
+    let mut spans = Vec::new();
+    let mut chars = Cursor { chars: source.chars().peekable(), line: 1, col: 1 };
+    let mut start = (1, 1);
 
     while let Some(&ch) = chars.peek() { //peek() returns an Option<&char>
+        //tokens pushed by the previous iteration started where it did
+        spans.resize(tokens.len(), start);
+        start = (chars.line, chars.col);
         //match on the character
         match ch { 
             ' ' | '\n' | '\r' | '\t' => { //skip whitespace
@@ -62,11 +139,23 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                 chars.next();
                 tokens.push(Token::Semicolon);
             }
-            '0'..='9' => { //number literal
-                let mut num = 0;
+            '0'..='9' => { //number literal: decimal, 0x hex or 0-prefixed octal
+                let mut radix = 10;
+                if ch == '0' {
+                    chars.next();
+                    match chars.peek() {
+                        Some('x') | Some('X') => {
+                            chars.next();
+                            radix = 16;
+                        }
+                        Some('0'..='7') => radix = 8,
+                        _ => {}
+                    }
+                }
+                let mut num: i64 = 0;
                 while let Some(c) = chars.peek() { 
-                    if c.is_digit(10) {
-                        num = num * 10 + c.to_digit(10).unwrap() as i64;
+                    if let Some(d) = c.to_digit(radix) {
+                        num = num.wrapping_mul(radix as i64).wrapping_add(d as i64);
                         chars.next();
                     } else {
                         break;
@@ -76,21 +165,65 @@ pub fn tokenize(source: &str) -> Vec<Token> {
             }
             '+' => { //addition
                 chars.next();
-                tokens.push(Token::Plus);
+                if let Some('+') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::PlusPlus); // '++'
+                } else if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::PlusEq); // '+='
+                } else {
+                    tokens.push(Token::Plus); // '+'
+                }
             }
             '*' => { //multiplication
                 chars.next();
-                tokens.push(Token::Star);
+                if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::StarEq); // '*='
+                } else {
+                    tokens.push(Token::Star); // '*'
+                }
             }
 
             '-' => { //subtraction
                 chars.next();
-                tokens.push(Token::Minus);
+                if let Some('>') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::Arrow); // '->'
+                } else if let Some('-') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::MinusMinus); // '--'
+                } else if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::MinusEq); // '-='
+                } else {
+                    tokens.push(Token::Minus); // '-'
+                }
+            }
+
+            '^' => { //bitwise xor
+                chars.next();
+                tokens.push(Token::Caret);
+            }
+
+            '~' => { //bitwise not
+                chars.next();
+                tokens.push(Token::Tilde);
+            }
+
+            '.' => { //member access
+                chars.next();
+                tokens.push(Token::Dot);
             }
 
             '%' => { //modulus
                 chars.next();
-                tokens.push(Token::Mod);
+                if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::ModEq); // '%='
+                } else {
+                    tokens.push(Token::Mod); // '%'
+                }
             }
 
             '=' => { //assignment
@@ -105,11 +238,57 @@ pub fn tokenize(source: &str) -> Vec<Token> {
 
             '<' => { //less than
                 chars.next();
-                tokens.push(Token::Less);
+                if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::LessEqual); // '<='
+                } else if let Some('<') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::Shl); // '<<'
+                } else {
+                    tokens.push(Token::Less); // '<'
+                }
             }
             '>' => { //greater than
                 chars.next();
-                tokens.push(Token::Greater);
+                if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::GreaterEqual); // '>='
+                } else if let Some('>') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::Shr); // '>>'
+                } else {
+                    tokens.push(Token::Greater); // '>'
+                }
+            }
+
+            '!' => { //logical not
+                chars.next();
+                if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::NotEqual); // '!='
+                } else {
+                    tokens.push(Token::Not); // '!'
+                }
+            }
+
+            '&' => { //logical and
+                chars.next();
+                if let Some('&') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::And); // '&&'
+                } else {
+                    tokens.push(Token::BitAnd); // '&'
+                }
+            }
+
+            '|' => { //logical or
+                chars.next();
+                if let Some('|') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::Or); // '||'
+                } else {
+                    tokens.push(Token::BitOr); // '|'
+                }
             }
 
             ',' => { //comma
@@ -117,6 +296,30 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                 tokens.push(Token::Comma);
             }
 
+            //character literal, e.g. 'a' or '\n'
+            '\'' => {
+                chars.next(); //consume opening quote
+                let c = match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('\'') => '\'',
+                        Some(other) => other,
+                        None => panic!("unterminated character literal"),
+                    },
+                    Some('\'') => panic!("empty character literal"),
+                    Some(c) => c,
+                    None => panic!("unterminated character literal"),
+                };
+                if chars.next() != Some('\'') {
+                    panic!("unterminated character literal");
+                }
+                tokens.push(Token::CharLiteral(c as i64));
+            }
+
             //string literal
             '"' => {
                 chars.next(); //consume opening quote
@@ -179,6 +382,11 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                         }
                     }
                 }
+                // a compound division "/="
+                else if chars.peek() == Some(&'=') {
+                    chars.next();
+                    tokens.push(Token::DivEq);
+                }
                 // a division operator
                 else {
                     tokens.push(Token::Div);
@@ -211,10 +419,15 @@ pub fn tokenize(source: &str) -> Vec<Token> {
                 } 
                 match ident.as_str() { //match on the identifier
                     "int" => tokens.push(Token::Int),
+                    "char" => tokens.push(Token::Char),
+                    "void" => tokens.push(Token::Void),
                     "return" => tokens.push(Token::Return),
                     "if" => tokens.push(Token::If),
                     "else" => tokens.push(Token::Else),
                     "while" => tokens.push(Token::While),
+                    "for" => tokens.push(Token::For),
+                    "break" => tokens.push(Token::Break),
+                    "continue" => tokens.push(Token::Continue),
                     _ => tokens.push(Token::Identifier(ident)),
                 }
 
@@ -226,5 +439,12 @@ pub fn tokenize(source: &str) -> Vec<Token> {
         }
     }
 
-    tokens //return the vector of tokens
+    spans.resize(tokens.len(), start);
+
+    //return the vector of tokens with their positions
+    tokens
+        .into_iter()
+        .zip(spans)
+        .map(|(token, (line, col))| SpannedToken { token, line, col })
+        .collect()
 }
@@ -0,0 +1,161 @@
+//! C4Rust: a small C4 compiler and stack-based VM.
+//!
+//! Every stage of the pipeline is available as a library, so a program can be
+//! built and run without going through the command line:
+//!
+//! ```
+//! use c4_rust_ghiyathi::vm::{Instruction, VmError, VM};
+//! # use std::{cell::RefCell, io, rc::Rc};
+//! # #[derive(Clone, Default)]
+//! # struct Sink(Rc<RefCell<Vec<u8>>>);
+//! # impl io::Write for Sink {
+//! #     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+//! #         self.0.borrow_mut().extend_from_slice(buf);
+//! #         Ok(buf.len())
+//! #     }
+//! #     fn flush(&mut self) -> io::Result<()> { Ok(()) }
+//! # }
+//!
+//! //printf("hi\n"); return 2 + 3;
+//! let program = vec![
+//!     Instruction::PrintfStr("hi\n".to_string()),
+//!     Instruction::IMM(2),
+//!     Instruction::IMM(3),
+//!     Instruction::ADD,
+//!     Instruction::EXIT,
+//! ];
+//!
+//! let mut vm = VM::new(program);
+//! //stand-in for stdout, the writer the VM prints to outside run_capturing
+//! let stdout = Sink::default();
+//! vm.set_output(Box::new(stdout.clone()));
+//! let (exit_value, output) = vm.run_capturing().unwrap();
+//! assert_eq!(exit_value, Some(5));
+//! assert_eq!(output, "hi\n");
+//! assert!(stdout.0.borrow().is_empty()); //nothing leaked past the capture
+//!
+//! //VM errors come back as values, along with the output printed before them
+//! let mut vm = VM::new(vec![
+//!     Instruction::PrintfStr("before\n".to_string()),
+//!     Instruction::IMM(1),
+//!     Instruction::IMM(0),
+//!     Instruction::DIV,
+//!     Instruction::EXIT,
+//! ]);
+//! let err = vm.run_capturing().unwrap_err();
+//! assert_eq!(err.error, VmError::DivByZero);
+//! assert_eq!(err.output, "before\n");
+//! ```
+
+pub mod codegen;
+pub mod ir;
+pub mod lexer;
+pub mod opt;
+pub mod parser;
+pub mod vm;
+
+use std::fmt;
+use std::panic::{self, UnwindSafe};
+
+///every artifact produced while compiling a source file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileResult {
+    pub tokens: Vec<lexer::Token>,
+    pub ast: codegen::ASTNode,
+    pub instructions: Vec<vm::Instruction>,
+}
+
+///a failure in one of the compiler stages, with the message it reported
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    Lex(String),
+    Parse(String),
+    Codegen(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Lex(msg) => write!(f, "lex error: {}", msg),
+            CompileError::Parse(msg) => write!(f, "parse error: {}", msg),
+            CompileError::Codegen(msg) => write!(f, "codegen error: {}", msg),
+        }
+    }
+}
+
+///runs the lexer, parser and code generator, returning the output of each stage
+///the stages report errors by panicking, so each one is caught and turned into a CompileError
+pub fn compile_full(source: &str) -> Result<CompileResult, CompileError> {
+    let tokens = run_stage(|| lexer::tokens(source)).map_err(CompileError::Lex)?;
+    let ast = run_stage(|| parser::parse(&tokens)).map_err(CompileError::Parse)?;
+    let instructions =
+        run_stage(|| codegen::generate_instructions(&ast)).map_err(CompileError::Codegen)?;
+    Ok(CompileResult { tokens, ast, instructions })
+}
+
+///outcome of one program run by run_batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchEntry {
+    pub name: String,
+    pub result: Result<i64, String>, //exit value, or the compile/VM error
+    pub output: String,              //what the program printed before it stopped
+}
+
+impl BatchEntry {
+    ///a program passes when it compiles and runs to EXIT without a VM error
+    pub fn passed(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+///compiles and runs each (name, source) pair, capturing its output
+///one failing program never stops the rest from running
+pub fn run_batch(programs: &[(String, String)]) -> Vec<BatchEntry> {
+    programs
+        .iter()
+        .map(|(name, source)| {
+            let (result, output) = match compile_full(source) {
+                Ok(compiled) => match vm::VM::new(compiled.instructions).run_capturing() {
+                    Ok((exit_value, output)) => (Ok(exit_value.unwrap_or(0)), output),
+                    Err(e) => (Err(e.error.to_string()), e.output),
+                },
+                Err(e) => (Err(e.to_string()), String::new()),
+            };
+            BatchEntry { name: name.clone(), result, output }
+        })
+        .collect()
+}
+
+///formats a pass/fail table for run_batch results, ending with the totals
+pub fn batch_summary(entries: &[BatchEntry]) -> String {
+    let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0).max("program".len());
+    let mut out = format!("{:<width$}  status  result\n", "program", width = width);
+    for entry in entries {
+        let (status, detail) = match &entry.result {
+            Ok(value) => ("pass", format!("exit {}", value)),
+            Err(e) => ("FAIL", e.clone()),
+        };
+        out.push_str(&format!("{:<width$}  {:<6}  {}\n", entry.name, status, detail, width = width));
+    }
+    let passed = entries.iter().filter(|e| e.passed()).count();
+    out.push_str(&format!(
+        "{} programs, {} passed, {} failed\n",
+        entries.len(),
+        passed,
+        entries.len() - passed
+    ));
+    out
+}
+
+//runs one stage, returning its panic message as the error
+fn run_stage<T>(stage: impl FnOnce() -> T + UnwindSafe) -> Result<T, String> {
+    panic::catch_unwind(stage).map_err(|payload| {
+        if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else {
+            "unknown error".to_string()
+        }
+    })
+}
@@ -1,9 +1,4 @@
-mod lexer;
-mod parser;
-mod vm;
-mod codegen;
-
-use codegen::Expr;
+use c4_rust_ghiyathi::{codegen, ir, lexer, opt, parser, vm};
 use std::fs;
 use clap::Parser;
 
@@ -20,51 +15,214 @@ struct Cli {
     #[arg(long)]
     ast: bool,
 
+    ///format for --ast: debug or json
+    #[arg(long, default_value = "debug")]
+    ast_format: codegen::AstFormat,
+
     ///trace VM execution step by step
     #[arg(long)]
     trace: bool,
 
+    ///fill new stack frames with 0xDEAD so uninitialized reads stand out
+    #[arg(long)]
+    scrub_frames: bool,
+
+    ///fold and propagate constants before generating code
+    #[arg(long)]
+    opt: bool,
+
+    ///show the three-address intermediate representation then exit
+    #[arg(long)]
+    dump_ir: bool,
+
+    ///print a checksum of the compiled program then exit
+    #[arg(long)]
+    checksum: bool,
+
+    ///print string literal statistics then exit
+    #[arg(long)]
+    stats: bool,
+
+    ///print the compiled instructions annotated with their stack effect then exit
+    #[arg(long)]
+    explain: bool,
+
+    ///print the total instruction count and each function's size then exit
+    #[arg(long)]
+    size: bool,
+
+    ///print a static estimate of the maximum stack depth then exit
+    #[arg(long)]
+    stack_estimate: bool,
+
+    ///value returned by the time() builtin
+    #[arg(long, default_value_t = 0)]
+    clock: i64,
+
+    ///make time() return the real system time instead of --clock
+    #[arg(long, conflicts_with = "clock")]
+    real_clock: bool,
+
+    ///when to flush printf output: line, full or none
+    #[arg(long, default_value = "line")]
+    output_buffering: vm::OutputBuffering,
+
+    ///deepest nesting of brackets and operators the parser accepts
+    #[arg(long, default_value_t = parser::DEFAULT_MAX_NESTING)]
+    max_nesting: usize,
+
+    ///compile and run every input file, then print a pass/fail summary
+    #[arg(long)]
+    batch: bool,
+
     ///input C4 source file
     input: String,
+
+    ///more source files, only accepted with --batch
+    #[arg(requires = "batch")]
+    more_inputs: Vec<String>,
+}
+
+impl Cli {
+    ///the clock selected by --clock / --real-clock
+    fn vm_clock(&self) -> vm::Clock {
+        if self.real_clock {
+            vm::Clock::Real
+        } else {
+            vm::Clock::Fixed(self.clock)
+        }
+    }
 }
 
+///runs every input file through the library and exits nonzero if any failed
+fn run_batch(cli: &Cli) -> ! {
+    let mut entries = Vec::new();
+    for path in std::iter::once(&cli.input).chain(&cli.more_inputs) {
+        match fs::read_to_string(path) {
+            Ok(source) => entries.extend(c4_rust_ghiyathi::run_batch(&[(path.clone(), source)])),
+            Err(e) => entries.push(c4_rust_ghiyathi::BatchEntry {
+                name: path.clone(),
+                result: Err(format!("failed to read source file: {}", e)),
+                output: String::new(),
+            }),
+        }
+    }
+    print!("{}", c4_rust_ghiyathi::batch_summary(&entries));
+    std::process::exit(if entries.iter().all(|e| e.passed()) { 0 } else { 1 });
+}
+
+///stack for the thread that compiles and runs the program
+///the parser and code generator recurse once per nesting level, so the main thread's
+///default stack would overflow well before --max-nesting is reached
+const COMPILER_STACK_SIZE: usize = 256 << 20;
+
 ///main function to run the compiler
 ///this is the entry point for the C4 Rust compiler and VM
-///reads a C file, tokenizes it, parses it into an AST
-///then generates VM instructions, and runs the program
 fn main() {
     //parse CLI flags
     let cli = Cli::parse();
 
+    let compiler = std::thread::Builder::new()
+        .stack_size(COMPILER_STACK_SIZE)
+        .spawn(move || run(cli))
+        .expect("failed to start the compiler thread");
+    if let Err(panic) = compiler.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+///reads a C file, tokenizes it, parses it into an AST
+///then generates VM instructions, and runs the program
+fn run(cli: Cli) {
+    if cli.batch {
+        run_batch(&cli);
+    }
+
     //read the source file
     let source = fs::read_to_string(&cli.input)
         .expect("Failed to read source file");
 
     //tokenize
-    let tokens = lexer::tokenize(&source);
+    let tokens = lexer::tokens(&source);
     if cli.tokens {
         println!("{:#?}", tokens);
         return;
     }
 
-    //parse to AST
-    let ast = parser::parse(&tokens);
+    //parse to AST, reporting programs nested too deeply instead of overflowing the stack
+    let mut ast = match parser::try_parse(&tokens, cli.max_nesting) {
+        Ok(ast) => ast,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
     if cli.ast {
-        println!("{:#?}", ast);
+        match cli.ast_format {
+            codegen::AstFormat::Debug => println!("{:#?}", ast),
+            codegen::AstFormat::Json => println!("{}", codegen::ast_to_json(&ast)),
+        }
+        return;
+    }
+    if cli.opt {
+        ast = opt::optimize(&ast);
+    }
+    if cli.dump_ir {
+        match ir::try_build_ir(&ast) {
+            Ok(ir) => print!("{}", ir),
+            Err(e) => {
+                eprintln!("error: --dump-ir: {}", e);
+                std::process::exit(1);
+            }
+        }
         return;
     }
 
     //generate a vector of VM instructions from the AST
-    let program = codegen::generate_instructions(&ast);
+    let (program, functions) = codegen::generate_program(&ast);
+    if cli.checksum {
+        println!("{:016x}", vm::checksum(&program));
+        return;
+    }
+    if cli.stats {
+        let stats = codegen::literal_stats(&program);
+        println!("string literals: {} unique, {} bytes", stats.unique, stats.bytes);
+        return;
+    }
+    if cli.size {
+        println!("total: {} instructions", program.len());
+        for (name, size) in codegen::function_sizes(&program, &functions) {
+            println!("  {}: {}", name, size);
+        }
+        return;
+    }
+    if cli.explain {
+        print!("{}", vm::disassemble(&program, true));
+        return;
+    }
+    if cli.stack_estimate {
+        match vm::estimate_max_stack(&program) {
+            Some(depth) => println!("estimated max stack depth: {}", depth),
+            None => println!("estimated max stack depth: unbounded"),
+        }
+        return;
+    }
 
     //create the VM
     let mut vm = vm::VM::new(program);
+    vm.set_functions(functions);
     if cli.trace {
         vm.enable_trace();
     }
+    if cli.scrub_frames {
+        vm.enable_frame_scrubbing();
+    }
+    vm.set_buffering(cli.output_buffering);
+    vm.set_clock(cli.vm_clock());
 
     //run the loaded program on the VM
-    vm.run();
+    let value = vm.run_or_panic();
+    println!("Program exited with value: {}", value);
 }
 
 
@@ -74,16 +232,25 @@ mod tests {
 
     use clap::Parser;
 
-    use crate::codegen::{ASTNode, Expr};
-    use crate::lexer::{tokenize, Token};
+    use crate::codegen::{ASTNode, Expr, Type};
+    use crate::lexer::{tokens, Token};
     use crate::parser::parse;
-    use crate::vm::{Instruction, VM};
+    use crate::vm::{CodeAddr, Instruction, VmError, VM};
+
+    //the AST the parser builds for 'int main() { <body> }'
+    fn main_def(body: Vec<ASTNode>) -> ASTNode {
+        ASTNode::Sequence(vec![ASTNode::FunctionDef {
+            name: "main".to_string(),
+            params: vec![],
+            body: Box::new(ASTNode::Sequence(body)),
+        }])
+    }
 
     #[test]
     fn test_tokenizer() {
         //verify basic tokens from a simple function definition
         let src = "int main() { return 42; }";
-        let tokens = tokenize(src);
+        let tokens = tokens(src);
 
         assert_eq!(tokens[0], Token::Int);
         assert_eq!(tokens[1], Token::Identifier("main".to_string()));
@@ -107,7 +274,7 @@ mod tests {
         ];
 
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
 
         assert_eq!(vm.stack, vec![5]);
     }
@@ -117,7 +284,7 @@ mod tests {
         //check BZ skips instructions when top of stack equals zero
         let program = vec![
             Instruction::IMM(0),
-            Instruction::BZ(5),
+            Instruction::BZ(CodeAddr(5)),
             Instruction::IMM(99),
             Instruction::IMM(100),
             Instruction::ADD,
@@ -126,7 +293,7 @@ mod tests {
         ];
 
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
 
         assert_eq!(vm.stack, vec![42]);
     }
@@ -136,7 +303,7 @@ mod tests {
         //check BNZ skips when top of stack is non-zero
         let program = vec![
             Instruction::IMM(1),
-            Instruction::BNZ(5),
+            Instruction::BNZ(CodeAddr(5)),
             Instruction::IMM(99),
             Instruction::IMM(100),
             Instruction::ADD,
@@ -145,16 +312,112 @@ mod tests {
         ];
 
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
 
         assert_eq!(vm.stack, vec![88]);
     }
 
+    #[test]
+    fn test_vm_budget_stops_infinite_loop() {
+        let mut vm = VM::new(vec![Instruction::JMP(CodeAddr(0))]);
+        assert_eq!(vm.run_with_budget(1000), Err(VmError::BudgetExceeded));
+        assert_eq!(vm.pc, 0);
+
+        //a program that finishes inside the budget runs normally
+        let program = vec![Instruction::IMM(2), Instruction::IMM(3), Instruction::ADD, Instruction::EXIT];
+        assert_eq!(VM::new(program.clone()).run_with_budget(4), Ok(5));
+        assert_eq!(VM::new(program.clone()).run_with_budget(3), Err(VmError::BudgetExceeded));
+
+        //step executes one instruction at a time
+        let mut vm = VM::new(program);
+        assert_eq!(vm.step(), Ok(true));
+        assert_eq!(vm.stack, vec![2]);
+        while vm.step().unwrap() {}
+        assert_eq!(vm.stack, vec![5]);
+        assert_eq!(vm.step(), Ok(false));
+    }
+
+    #[test]
+    fn test_vm_bitwise_operators() {
+        let eval = |program: Vec<Instruction>| VM::new(program).run();
+        let binary = |a: i64, b: i64, op: Instruction| {
+            eval(vec![Instruction::IMM(a), Instruction::IMM(b), op, Instruction::EXIT])
+        };
+        assert_eq!(binary(6, 3, Instruction::AND), Ok(2));
+        assert_eq!(binary(6, 3, Instruction::OR), Ok(7));
+        assert_eq!(binary(6, 3, Instruction::XOR), Ok(5));
+        assert_eq!(binary(1, 4, Instruction::SHL), Ok(16));
+        assert_eq!(binary(-16, 2, Instruction::SHR), Ok(-4));
+        assert_eq!(eval(vec![Instruction::IMM(0), Instruction::BNOT, Instruction::EXIT]), Ok(-1));
+    }
+
+    #[test]
+    fn test_bitwise_operators_end_to_end() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+        use crate::opt::optimize;
+
+        let run = |src: &str| {
+            let ast = parse(&tokens(src));
+            let direct = VM::new(generate_instructions(&ast)).run();
+            assert_eq!(VM::new(generate_instructions(&optimize(&ast))).run(), direct, "{}", src);
+            assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), direct, "{}", src);
+            direct
+        };
+        assert_eq!(run("int main() { int a = 6; return a & 3; }"), Ok(2));
+        assert_eq!(run("int main() { int a = 1; return a << 4; }"), Ok(16));
+        assert_eq!(run("int main() { int a = 0; return ~a; }"), Ok(-1));
+        //C precedence: shifts under '+', '&' under '==', then '^', then '|'
+        assert_eq!(run("int main() { return 1 << 1 + 1; }"), Ok(4));
+        assert_eq!(run("int main() { return 3 & 1 == 1; }"), Ok(1));
+        assert_eq!(run("int main() { return 1 | 6 ^ 3 & 2; }"), Ok(5));
+    }
+
+    #[test]
+    fn test_vm_nop_changes_nothing() {
+        let program = vec![
+            Instruction::ENT(1),
+            Instruction::IMM(6),
+            Instruction::STORE(0),
+            Instruction::LOAD(0),
+            Instruction::IMM(7),
+            Instruction::MUL,
+            Instruction::EXIT,
+        ];
+        let mut padded = vec![Instruction::NOP];
+        for instr in &program {
+            padded.push(instr.clone());
+            padded.push(Instruction::NOP);
+        }
+
+        let mut plain = VM::new(program);
+        let mut vm = VM::new(padded);
+        assert_eq!(vm.run(), Ok(42));
+        assert_eq!(plain.run(), Ok(42));
+        assert_eq!(vm.stack, plain.stack);
+    }
+
+    #[test]
+    fn test_vm_exit_reads_top_of_stack() {
+        //main's locals stay where they are, the exit value is just the top
+        let program = vec![
+            Instruction::ENT(2),
+            Instruction::IMM(3),
+            Instruction::STORE(1),
+            Instruction::IMM(9),
+            Instruction::EXIT,
+        ];
+
+        let mut vm = VM::new(program);
+        assert_eq!(vm.run(), Ok(9));
+        assert_eq!(vm.stack, vec![0, 0, 3, 9]);
+    }
+
     #[test]
     fn test_vm_function_call() {
         //check JSR and LEV manage function call and return value
         let program = vec![
-            Instruction::JSR(4),
+            Instruction::JSR(CodeAddr(4)),
             Instruction::IMM(42),
             Instruction::PSH,
             Instruction::EXIT,
@@ -163,11 +426,76 @@ mod tests {
         ];
 
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
 
         assert_eq!(vm.stack.last(), Some(&42));
     }
 
+    #[test]
+    fn test_vm_lev_without_frame_stops() {
+        //a LEV with bp == 0 must stop the VM instead of underflowing bp - 1
+        let program = vec![Instruction::IMM(1), Instruction::LEV, Instruction::IMM(2), Instruction::EXIT];
+
+        let mut vm = VM::new(program);
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+
+        assert_eq!(vm.pc, 1);
+        assert_eq!(vm.stack, vec![1]);
+    }
+
+    #[test]
+    fn test_vm_div_and_mod_by_zero() {
+        //a zero divisor is reported with pc left at the failing instruction
+        let mut vm = VM::new(vec![Instruction::IMM(5), Instruction::IMM(0), Instruction::DIV, Instruction::EXIT]);
+        assert_eq!(vm.run(), Err(VmError::DivByZero));
+        assert_eq!(vm.pc, 2);
+
+        let mut vm = VM::new(vec![Instruction::IMM(5), Instruction::IMM(0), Instruction::MOD, Instruction::EXIT]);
+        assert_eq!(vm.run(), Err(VmError::DivByZero));
+        assert_eq!(vm.pc, 2);
+    }
+
+    #[test]
+    fn test_vm_runaway_recursion_overflows() {
+        use crate::codegen::generate_instructions;
+        use crate::vm::HEAP_BASE;
+
+        let program = generate_instructions(&parse(&tokens("int f() { return f(); } int main() { return f(); }")));
+        let mut vm = VM::new(program);
+        vm.set_max_stack(1000);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+        assert!(vm.stack.len() <= 1000);
+
+        //a single oversized frame is refused before it is allocated
+        let mut vm = VM::new(vec![Instruction::ENT(1 << 40), Instruction::EXIT]);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+
+        //the stack can never grow into heap addresses, whatever limit is asked for
+        let mut vm = VM::new(vec![Instruction::IMM(1), Instruction::JMP(CodeAddr(0))]);
+        vm.set_max_stack(usize::MAX);
+        assert_eq!(vm.max_stack, HEAP_BASE);
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+        assert_eq!(vm.stack.len(), HEAP_BASE);
+
+        let mut vm = VM::new(vec![Instruction::ENT(HEAP_BASE), Instruction::EXIT]);
+        vm.max_stack = usize::MAX;
+        assert_eq!(vm.run(), Err(VmError::StackOverflow));
+    }
+
+    #[test]
+    fn test_vm_stack_underflow_is_an_error() {
+        //a bare ADD has nothing to pop, which must come back as an error, not a panic
+        let mut vm = VM::new(vec![Instruction::ADD, Instruction::EXIT]);
+        assert_eq!(vm.run(), Err(VmError::StackUnderflow));
+
+        //running past the end is reported too
+        let mut vm = VM::new(vec![Instruction::IMM(1)]);
+        assert_eq!(vm.run(), Err(VmError::PcOutOfBounds));
+
+        let mut vm = VM::new(vec![Instruction::IMM(2), Instruction::IMM(3), Instruction::ADD, Instruction::EXIT]);
+        assert_eq!(vm.run(), Ok(5));
+    }
+
     #[test]
     fn test_vm_memory_access() {
         //test LEA, SI, and LI for local variable storage and retrieval
@@ -182,134 +510,628 @@ mod tests {
         ];
 
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
 
         assert_eq!(vm.stack.last(), Some(&99));
     }
 
     #[test]
-    fn test_vm_syscall_stubs() {
-        //validate that placeholder syscalls pushes dummy values
+    fn test_vm_byte_segment_lc_sc() {
+        use crate::vm::{BYTES_BASE, MALC_BYTES};
+
+        //p = malloc(2 bytes); p[0] = 'H'; p[1] = 'i'; then read both back with LC
         let program = vec![
-            Instruction::IMM(100),
-            Instruction::IMM(1),
+            Instruction::IMM(2),
+            Instruction::IMM(MALC_BYTES),
             Instruction::MALC,
-            Instruction::IMM(3),
-            Instruction::CLOS,
+            Instruction::PSH,
+            Instruction::IMM('H' as i64),
+            Instruction::SC,
+            Instruction::PSH,
+            Instruction::IMM(1),
+            Instruction::ADD,
+            Instruction::IMM(0x100 + 'i' as i64), //only the low byte is kept
+            Instruction::SC,
+            Instruction::PSH,
+            Instruction::LC,
+            Instruction::IMM(BYTES_BASE as i64 + 1),
+            Instruction::LC,
             Instruction::EXIT,
         ];
-
         let mut vm = VM::new(program);
-        vm.run();
-
-        assert_eq!(vm.stack, vec![0, 0x1000, 0]);
+        assert_eq!(vm.run(), Ok('i' as i64));
+        assert_eq!(vm.bytes, b"Hi".to_vec());
+        assert_eq!(vm.stack, vec![0, BYTES_BASE as i64, 'H' as i64, 'i' as i64]);
+        assert!(vm.heap.is_empty());
+
+        //word loads never reach into the byte segment
+        let mut vm = VM::new(vec![
+            Instruction::IMM(8),
+            Instruction::IMM(MALC_BYTES),
+            Instruction::MALC,
+            Instruction::LI,
+            Instruction::EXIT,
+        ]);
+        assert_eq!(vm.run(), Err(VmError::BadAddress(BYTES_BASE)));
     }
 
     #[test]
-    fn test_parser_return_add() {
-        //parse a return statement with an expression 2+3
-        use crate::codegen::{ASTNode, Expr};
+    fn test_vm_heap_alloc_store_load_free() {
+        use crate::vm::HEAP_BASE;
 
-        let tokens = tokenize("int main() { return 2 + 3; }");
-        let ast = parse(&tokens);
-        assert_eq!(
-            ast,
-            ASTNode::Sequence(vec![
-                ASTNode::Return(Box::new(Expr::Add(
-                    Box::new(Expr::Number(2)),
-                    Box::new(Expr::Number(3))
-                )))
-            ])
-        );
+        //p = malloc(4); p[1] = 42; return p[1];  (MALC leaves status, then the pointer)
+        let program = vec![
+            Instruction::IMM(4),
+            Instruction::IMM(0),
+            Instruction::MALC,
+            Instruction::PSH,
+            Instruction::IMM(1),
+            Instruction::ADD,
+            Instruction::IMM(42),
+            Instruction::SI,
+            Instruction::PSH,
+            Instruction::IMM(1),
+            Instruction::ADD,
+            Instruction::LI,
+            Instruction::EXIT,
+        ];
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+        assert_eq!(vm.stack, vec![0, HEAP_BASE as i64, 42]);
+        assert_eq!(vm.heap, vec![0, 42, 0, 0]);
+
+        //a freed region is handed out again by the next MALC
+        let program = vec![
+            Instruction::IMM(4),
+            Instruction::IMM(0),
+            Instruction::MALC,
+            Instruction::FREE,
+            Instruction::IMM(2),
+            Instruction::IMM(0),
+            Instruction::MALC,
+            Instruction::EXIT,
+        ];
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+        assert_eq!(vm.stack, vec![0, 0, HEAP_BASE as i64]);
+
+        //freeing something that was never allocated is an error
+        let mut vm = VM::new(vec![Instruction::IMM(7), Instruction::FREE, Instruction::EXIT]);
+        assert_eq!(vm.run(), Err(VmError::BadAddress(7)));
     }
 
     #[test]
-    fn test_codegen_add() {
-        ///ensure generate_instructions outputs correct sequence for 2+3
-        use crate::codegen::{generate_instructions, ASTNode, Expr};
-        use crate::vm::Instruction;
+    fn test_vm_scrub_frames() {
+        use crate::vm::SCRUB_SENTINEL;
 
-        let ast = ASTNode::Sequence(vec![ASTNode::Return(Box::new(Expr::Add(
-            Box::new(Expr::Number(2)),
-            Box::new(Expr::Number(3)),
-        )))]);
+        //set(): writes 5 into its local; peek(): returns its local without writing it
+        let program = vec![
+            Instruction::JSR(CodeAddr(4)),
+            Instruction::JSR(CodeAddr(9)),
+            Instruction::PSH,
+            Instruction::EXIT,
+            Instruction::ENT(1), //set
+            Instruction::IMM(5),
+            Instruction::STORE(0),
+            Instruction::IMM(0),
+            Instruction::LEV,
+            Instruction::ENT(1), //peek, reuses the slot set() just left
+            Instruction::LOAD(0),
+            Instruction::LEV,
+        ];
 
-        let instructions = generate_instructions(&ast);
+        let mut vm = VM::new(program.clone());
+        vm.enable_frame_scrubbing();
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&SCRUB_SENTINEL));
 
-        assert_eq!(
-            instructions,
-            vec![
-                Instruction::ENT(0),
-                Instruction::IMM(2),
-                Instruction::IMM(3),
-                Instruction::ADD,
-                Instruction::PSH,
-                Instruction::EXIT,
-            ]
-        );
-    }
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&0));
 
+        assert!(Cli::parse_from(["c4rust", "--scrub-frames", "foo.c"]).scrub_frames);
+    }
 
-    ///verify parser handles operator precedence, multiplication before addition
     #[test]
-    fn test_parser_add_multiply() {
-        ///verify parser handles precedence: 1 + 2 * 3
+    fn test_vm_snapshot_and_restore() {
+        //a snapshot written out and read back resumes to the same result
+        use crate::vm::VmSnapshot;
 
-        use crate::codegen::{ASTNode, Expr};
+        let program = vec![
+            Instruction::ENT(1),
+            Instruction::LEA(0),
+            Instruction::IMM(6),
+            Instruction::SI,
+            Instruction::LEA(0),
+            Instruction::LI,
+            Instruction::IMM(7),
+            Instruction::MUL,
+            Instruction::EXIT,
+        ];
 
-        let tokens = tokenize("int main() { return 1 + 2 * 3; }");
-        let ast = parse(&tokens);
+        //start the VM partway through, with the local already stored
+        let mut vm = VM::new(program);
+        vm.restore(VmSnapshot { stack: vec![0, 6], pc: 4, bp: 1, running: true, ..VmSnapshot::default() });
+        let saved = vm.snapshot().to_string();
+        assert_eq!(saved, "pc=4 bp=1 running=1 stack=0,6");
 
-        assert_eq!(
-            ast,
-            ASTNode::Sequence(vec![
-                ASTNode::Return(Box::new(Expr::Add(
-                    Box::new(Expr::Number(1)),
-                    Box::new(Expr::Mul(
-                        Box::new(Expr::Number(2)),
-                        Box::new(Expr::Number(3)),
-                    ))
-                )))
-            ])
-        );
+        vm.run_or_panic();
+        let first = vm.stack.clone();
+
+        let snap: VmSnapshot = saved.parse().unwrap();
+        vm.restore(snap);
+        vm.run_or_panic();
+
+        //EXIT leaves the frame below the exit value alone
+        assert_eq!(first, vec![0, 6, 42]);
+        assert_eq!(vm.stack, first);
     }
 
     #[test]
-    fn test_parser_with_parentheses() {
-        ///check parser respects parentheses: (1 + 2) * 3
-        use crate::codegen::{ASTNode, Expr};
-        let tokens = tokenize("int main() { return (1 + 2) * 3; }");
-        let ast = parse(&tokens);
+    fn test_vm_snapshot_keeps_heap_and_allocators() {
+        //a snapshot taken after MALC/SI/FREE brings back the memory and what is allocated
+        use crate::vm::{VmSnapshot, BYTES_BASE, HEAP_BASE};
+
+        let heap = HEAP_BASE as i64;
+        let program = vec![
+            Instruction::IMM(2),
+            Instruction::IMM(0),
+            Instruction::MALC, //heap + 0
+            Instruction::IMM(2),
+            Instruction::IMM(0),
+            Instruction::MALC, //heap + 2
+            Instruction::IMM(41),
+            Instruction::SI,
+            Instruction::POP,
+            Instruction::FREE, //heap + 0 goes back on the free list
+            Instruction::IMM(1),
+            Instruction::IMM(crate::vm::MALC_BYTES),
+            Instruction::MALC, //first byte
+            Instruction::IMM(104),
+            Instruction::SC,
+            //snapshot taken here, at pc 15
+            Instruction::IMM(1),
+            Instruction::IMM(0),
+            Instruction::MALC, //reuses heap + 0
+            Instruction::IMM(heap + 2),
+            Instruction::LI,
+            Instruction::IMM(BYTES_BASE as i64),
+            Instruction::LC,
+            Instruction::ADD,
+            Instruction::IMM(heap + 2),
+            Instruction::FREE, //only valid if the live region came back too
+            Instruction::EXIT,
+        ];
 
+        let mut vm = VM::new(program.clone());
+        assert_eq!(vm.run_with_budget(15), Err(VmError::BudgetExceeded));
+        let saved = vm.snapshot().to_string();
         assert_eq!(
-            ast,
-            ASTNode::Sequence(vec![
-                ASTNode::Return(Box::new(Expr::Mul(
-                    Box::new(Expr::Add(
-                        Box::new(Expr::Number(1)),
-                        Box::new(Expr::Number(2))
-                    )),
-                    Box::new(Expr::Number(3))
-                )))
-            ])
+            saved,
+            "pc=15 bp=0 running=1 stack=0,0 heap=0,0,41,0 heap_live=2:2 heap_free=0:2 bytes=68 bytes_live=0:1 bytes_free="
         );
+        assert_eq!(vm.run(), Ok(145));
+        let first = vm.stack.clone();
+        assert_eq!(first, vec![0, 0, 0, heap, 145]);
+
+        //a fresh VM restored from the text runs to the same state
+        let mut resumed = VM::new(program);
+        resumed.restore(saved.parse::<VmSnapshot>().unwrap());
+        assert_eq!(resumed.run(), Ok(145));
+        assert_eq!(resumed.stack, first);
+        assert_eq!(resumed.snapshot(), vm.snapshot());
     }
 
     #[test]
-    fn test_nested_parentheses_expression() {
-        ///test nested parentheses expression evaluation
-        use crate::codegen::{ASTNode, Expr};
+    fn test_vm_reset_and_rerun() {
+        //running the same VM twice after a reset gives the same result
+        let tokens = tokens("int main() { int x = 4; x = x * 5; return x + 1; }");
+        let program = crate::codegen::generate_instructions(&parse(&tokens));
 
-        let tokens = tokenize("int main() { return (1 + 2) * (4 - 1); }");
-        let ast = parse(&tokens);
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+        let first = vm.stack.last().copied();
 
-        assert_eq!(
-            ast,
-            ASTNode::Sequence(vec![
-                ASTNode::Return(Box::new(Expr::Mul(
-                    Box::new(Expr::Add(
-                        Box::new(Expr::Number(1)),
-                        Box::new(Expr::Number(2))
+        vm.reset();
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.pc, 0);
+        vm.run_or_panic();
+
+        assert_eq!(first, Some(21));
+        assert_eq!(vm.stack.last().copied(), first);
+
+        //files left open by the first run are dropped and fds are numbered from the start again
+        let path = std::env::temp_dir().join(format!("c4rust_reset_{}.txt", std::process::id()));
+        std::fs::write(&path, "x").unwrap();
+        let program = vec![
+            Instruction::IMM(crate::vm::BYTES_BASE as i64),
+            Instruction::IMM(0),
+            Instruction::OPEN,
+            Instruction::IMM(4),
+            Instruction::CLOS, //fd 4 is only open if the first run's file survived the reset
+            Instruction::EXIT,
+        ];
+        let mut vm = VM::new(program);
+        vm.enable_syscalls = true;
+        let mut path_bytes = path.to_str().unwrap().as_bytes().to_vec();
+        path_bytes.push(0);
+        vm.bytes = path_bytes.clone();
+        vm.run_or_panic();
+        let first = vm.stack.clone();
+
+        vm.reset();
+        vm.bytes = path_bytes;
+        vm.run_or_panic();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first, vec![3, -1]);
+        assert_eq!(vm.stack, first);
+    }
+
+    #[test]
+    fn test_vm_run_capturing() {
+        //compile from source and capture both the result and printed text
+        let tokens = tokens(r#"int main() { printf("one\n"); printf("two\n"); return 9; }"#);
+        let program = crate::codegen::generate_instructions(&parse(&tokens));
+
+        let mut vm = VM::new(program);
+        let (exit_value, output) = vm.run_capturing().unwrap();
+
+        assert_eq!(exit_value, Some(9));
+        assert_eq!(output, "one\ntwo\n");
+
+        //a VM error is returned with the output printed before it, not panicked on
+        let src = r#"int main() { int x = 0; printf("one\n"); return 1 % x; }"#;
+        let mut vm = VM::new(crate::codegen::generate_instructions(&parse(&crate::lexer::tokens(src))));
+        let err = vm.run_capturing().unwrap_err();
+        assert_eq!(err.error, VmError::DivByZero);
+        assert_eq!(err.output, "one\n");
+        assert_eq!(err.to_string(), "division by zero");
+    }
+
+    #[test]
+    fn test_vm_output_failure_is_an_error() {
+        //a writer that fails stops the VM with a VmError instead of a panic
+        struct Broken;
+        impl std::io::Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let mut vm = VM::new(vec![Instruction::PrintfStr("hi\n".to_string()), Instruction::EXIT]);
+        vm.set_output(Box::new(Broken));
+        assert_eq!(vm.run(), Err(VmError::Output(std::io::ErrorKind::BrokenPipe)));
+        assert_eq!(vm.pc, 0);
+
+        //flushing at EXIT fails the same way
+        let mut vm = VM::new(vec![Instruction::IMM(1), Instruction::EXIT]);
+        vm.set_output(Box::new(Broken));
+        assert_eq!(vm.run(), Err(VmError::Output(std::io::ErrorKind::BrokenPipe)));
+        assert_eq!(vm.pc, 1);
+    }
+
+    #[test]
+    fn test_vm_stack_estimate_matches_peak() {
+        //for straight-line code the static estimate is exact
+        use crate::vm::estimate_max_stack;
+
+        let tokens = tokens("int main() { int x = 2; return (x + 3) * (x + 4); }");
+        let program = crate::codegen::generate_instructions(&parse(&tokens));
+        let estimate = estimate_max_stack(&program);
+
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+
+        assert_eq!(estimate, Some(5));
+        assert_eq!(estimate, Some(vm.peak_stack));
+    }
+
+    #[test]
+    fn test_vm_stack_estimate_unbounded_loop() {
+        //a loop that pushes on every iteration has no static bound
+        use crate::vm::estimate_max_stack;
+
+        let program = vec![Instruction::IMM(1), Instruction::JMP(CodeAddr(0))];
+        assert_eq!(estimate_max_stack(&program), None);
+    }
+
+    #[test]
+    fn test_vm_printf_percent_escapes() {
+        //%% is a literal percent sign, a trailing lone % is kept as-is
+        use crate::vm::format_printf;
+
+        let tokens = tokens(r#"int main() { printf("100%%\n"); return 0; }"#);
+        let mut vm = VM::new(crate::codegen::generate_instructions(&parse(&tokens)));
+        let (_, output) = vm.run_capturing().unwrap();
+        assert_eq!(output, "100%\n");
+
+        assert_eq!(format_printf("%%d", &[]), "%d");
+        assert_eq!(format_printf("50%", &[]), "50%");
+        assert_eq!(format_printf("{%d}", &[7]), "{7}");
+        assert_eq!(format_printf("%d", &[]), "%d");
+    }
+
+    #[test]
+    fn test_vm_checksum_is_stable() {
+        //same source gives the same checksum, a changed source a different one
+        use crate::vm::checksum;
+
+        let compile = |src: &str| crate::codegen::generate_instructions(&parse(&tokens(src)));
+        let a = checksum(&compile("int main() { int x = 2; return x * 3; }"));
+        let b = checksum(&compile("int main() { int x = 2; return x * 3; }"));
+        let c = checksum(&compile("int main() { int x = 2; return x * 4; }"));
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        //FNV-1a offset basis for an empty program
+        assert_eq!(checksum(&[]), 0xcbf29ce484222325);
+    }
+
+    #[test]
+    fn test_vm_line_buffered_output() {
+        //line buffering should flush once per completed line
+        use crate::vm::OutputBuffering;
+        use std::cell::RefCell;
+        use std::io::Write;
+        use std::rc::Rc;
+
+        //records the data written between consecutive flushes
+        struct FlushRecorder {
+            pending: String,
+            flushed: Rc<RefCell<Vec<String>>>,
+        }
+
+        impl Write for FlushRecorder {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.pending.push_str(std::str::from_utf8(buf).unwrap());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                if !self.pending.is_empty() {
+                    self.flushed.borrow_mut().push(std::mem::take(&mut self.pending));
+                }
+                Ok(())
+            }
+        }
+
+        let program = vec![
+            Instruction::PrintfStr("a\n".to_string()),
+            Instruction::PrintfStr("b".to_string()),
+            Instruction::PrintfStr("c\n".to_string()),
+            Instruction::IMM(0),
+            Instruction::EXIT,
+        ];
+        let flushed = Rc::new(RefCell::new(Vec::new()));
+
+        let mut vm = VM::new(program);
+        vm.set_buffering(OutputBuffering::Line);
+        vm.set_output(Box::new(FlushRecorder { pending: String::new(), flushed: flushed.clone() }));
+        vm.run_or_panic();
+
+        assert_eq!(*flushed.borrow(), vec!["a\n".to_string(), "bc\n".to_string()]);
+        assert_eq!("full".parse::<OutputBuffering>(), Ok(OutputBuffering::Full));
+    }
+
+    #[test]
+    fn test_vm_syscall_stubs() {
+        //validate that syscalls push their results (MALC hands out the first heap address)
+        let program = vec![
+            Instruction::IMM(100),
+            Instruction::IMM(0), //flags: plain heap words
+            Instruction::MALC,
+            Instruction::IMM(3),
+            Instruction::CLOS,
+            Instruction::EXIT,
+        ];
+
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+
+        assert_eq!(vm.stack, vec![0, crate::vm::HEAP_BASE as i64, 0]);
+    }
+
+    #[test]
+    fn test_vm_malc_too_large_fails() {
+        //sizes that cannot fit push status -1 and a null pointer instead of aborting
+        use crate::vm::{BYTES_BASE, HEAP_BASE, MALC_BYTES};
+
+        let program = vec![
+            Instruction::IMM(i64::MAX),
+            Instruction::IMM(0),
+            Instruction::MALC,
+            Instruction::IMM((BYTES_BASE - HEAP_BASE) as i64 + 1), //one past the heap's address range
+            Instruction::IMM(0),
+            Instruction::MALC,
+            Instruction::IMM(i64::MAX),
+            Instruction::IMM(MALC_BYTES),
+            Instruction::MALC,
+            Instruction::IMM(4), //a failed request leaves the heap usable
+            Instruction::IMM(0),
+            Instruction::MALC,
+            Instruction::EXIT,
+        ];
+
+        let mut vm = VM::new(program);
+        assert_eq!(vm.run(), Ok(HEAP_BASE as i64));
+        assert_eq!(vm.stack, vec![-1, 0, -1, 0, -1, 0, 0, HEAP_BASE as i64]);
+        assert_eq!(vm.heap.len(), 4);
+        assert!(vm.bytes.is_empty());
+    }
+
+    #[test]
+    fn test_vm_adj_keeps_return_value() {
+        //two args, a call to a routine returning 42, then ADJ(2) leaves only the result
+        let program = vec![
+            Instruction::IMM(1),
+            Instruction::IMM(2),
+            Instruction::JSR(CodeAddr(5)),
+            Instruction::ADJ(2),
+            Instruction::EXIT,
+            Instruction::ENT(0),
+            Instruction::IMM(42),
+            Instruction::LEV,
+        ];
+
+        let mut vm = VM::new(program);
+        assert_eq!(vm.run(), Ok(42));
+        assert_eq!(vm.stack, vec![42]);
+    }
+
+    #[test]
+    fn test_vm_file_syscalls() {
+        //OPEN/READ/CLOS on a real file once syscalls are enabled
+        use crate::vm::BYTES_BASE;
+
+        let path = std::env::temp_dir().join(format!("c4rust_syscalls_{}.txt", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+        let buf = BYTES_BASE + 256;
+        let program = vec![
+            Instruction::IMM(BYTES_BASE as i64),
+            Instruction::IMM(0),
+            Instruction::OPEN,
+            Instruction::IMM(3),
+            Instruction::IMM(buf as i64),
+            Instruction::IMM(i64::MAX), //only the 256 bytes left after buf are read into
+            Instruction::READ,
+            Instruction::IMM(3),
+            Instruction::CLOS,
+            Instruction::IMM(3),
+            Instruction::CLOS, //already closed
+            Instruction::EXIT,
+        ];
+
+        let mut vm = VM::new(program);
+        vm.enable_syscalls = true;
+        //the path goes at the start of the byte segment, the read buffer after it
+        vm.bytes = vec![0; 512];
+        let path_str = path.to_str().unwrap();
+        vm.bytes[..path_str.len()].copy_from_slice(path_str.as_bytes());
+        let result = vm.run();
+
+        assert_eq!(result, Ok(-1));
+        assert_eq!(vm.stack, vec![3, 5, 0, -1]);
+        assert_eq!(&vm.bytes[256..261], b"hello");
+
+        //with only 2 bytes of room after buf, READ stops there instead of overrunning the segment
+        vm.reset();
+        vm.bytes = vec![0; 258];
+        vm.bytes[..path_str.len()].copy_from_slice(path_str.as_bytes());
+        let result = vm.run();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result, Ok(-1));
+        assert_eq!(vm.stack, vec![3, 2, 0, -1]);
+        assert_eq!(&vm.bytes[256..], b"he");
+    }
+
+    #[test]
+    fn test_parser_return_add() {
+        //parse a return statement with an expression 2+3
+        use crate::codegen::{ASTNode, Expr};
+
+        let tokens = tokens("int main() { return 2 + 3; }");
+        let ast = parse(&tokens);
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Return(Box::new(Expr::Add(
+                    Box::new(Expr::Number(2)),
+                    Box::new(Expr::Number(3))
+                )))
+            ])
+        );
+    }
+
+    #[test]
+    fn test_codegen_add() {
+        ///ensure generate_instructions outputs correct sequence for 2+3
+        use crate::codegen::{generate_instructions, ASTNode, Expr};
+        use crate::vm::Instruction;
+
+        let ast = ASTNode::Sequence(vec![ASTNode::Return(Box::new(Expr::Add(
+            Box::new(Expr::Number(2)),
+            Box::new(Expr::Number(3)),
+        )))]);
+
+        let instructions = generate_instructions(&ast);
+
+        assert_eq!(
+            instructions,
+            vec![
+                Instruction::ENT(0),
+                Instruction::IMM(2),
+                Instruction::IMM(3),
+                Instruction::ADD,
+                Instruction::EXIT,
+            ]
+        );
+    }
+
+
+    ///verify parser handles operator precedence, multiplication before addition
+    #[test]
+    fn test_parser_add_multiply() {
+        ///verify parser handles precedence: 1 + 2 * 3
+        use crate::codegen::{ASTNode, Expr};
+
+        let tokens = tokens("int main() { return 1 + 2 * 3; }");
+        let ast = parse(&tokens);
+
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Return(Box::new(Expr::Add(
+                    Box::new(Expr::Number(1)),
+                    Box::new(Expr::Mul(
+                        Box::new(Expr::Number(2)),
+                        Box::new(Expr::Number(3)),
+                    ))
+                )))
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parser_with_parentheses() {
+        ///check parser respects parentheses: (1 + 2) * 3
+        use crate::codegen::{ASTNode, Expr};
+        let tokens = tokens("int main() { return (1 + 2) * 3; }");
+        let ast = parse(&tokens);
+
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Return(Box::new(Expr::Mul(
+                    Box::new(Expr::Add(
+                        Box::new(Expr::Number(1)),
+                        Box::new(Expr::Number(2))
+                    )),
+                    Box::new(Expr::Number(3))
+                )))
+            ])
+        );
+    }
+
+    #[test]
+    fn test_nested_parentheses_expression() {
+        ///test nested parentheses expression evaluation
+        use crate::codegen::{ASTNode, Expr};
+
+        let tokens = tokens("int main() { return (1 + 2) * (4 - 1); }");
+        let ast = parse(&tokens);
+
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Return(Box::new(Expr::Mul(
+                    Box::new(Expr::Add(
+                        Box::new(Expr::Number(1)),
+                        Box::new(Expr::Number(2))
                     )),
                     Box::new(Expr::Sub(
                         Box::new(Expr::Number(4)),
@@ -323,9 +1145,9 @@ mod tests {
     #[test]
     fn test_tokenizer_assignment_and_equality() {
         ///test tokenizer for assignment and equality operators
-        use crate::lexer::{tokenize, Token};
+        use crate::lexer::{tokens, Token};
 
-        let tokens = tokenize("int x = 5; if (x == 5) { return x; }");
+        let tokens = tokens("int x = 5; if (x == 5) { return x; }");
 
         let expected = vec![
             Token::Int,
@@ -349,25 +1171,206 @@ mod tests {
         assert_eq!(tokens, expected);
     }
 
+    #[test]
+    fn test_tokenizer_two_char_comparisons() {
+        //<=, >= and != are single tokens, a lone < or ! is not
+        let id = |s: &str| Token::Identifier(s.to_string());
+
+        assert_eq!(tokens("a <= b"), vec![id("a"), Token::LessEqual, id("b")]);
+        assert_eq!(tokens("a >= b"), vec![id("a"), Token::GreaterEqual, id("b")]);
+        assert_eq!(tokens("a != b"), vec![id("a"), Token::NotEqual, id("b")]);
+        assert_eq!(tokens("!a"), vec![Token::Not, id("a")]);
+
+        //back to back: '<' must not swallow the following '<='
+        assert_eq!(
+            tokens("a < b<=c>d>=e"),
+            vec![
+                id("a"), Token::Less, id("b"), Token::LessEqual, id("c"),
+                Token::Greater, id("d"), Token::GreaterEqual, id("e"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_arrow_and_dot() {
+        //'->' is one token, while '-' followed by a space is still subtraction
+        let id = |s: &str| Token::Identifier(s.to_string());
+
+        assert_eq!(tokens("a->b"), vec![id("a"), Token::Arrow, id("b")]);
+        assert_eq!(tokens("a - b"), vec![id("a"), Token::Minus, id("b")]);
+        assert_eq!(tokens("a.b"), vec![id("a"), Token::Dot, id("b")]);
+    }
+
+    #[test]
+    fn test_tokenizer_line_and_column() {
+        use crate::lexer::tokenize;
+
+        let spanned = tokenize("int main(){\n  if (x) return 1;\n}");
+        let if_tok = spanned.iter().find(|t| t.token == Token::If).unwrap();
+        assert_eq!((if_tok.line, if_tok.col), (2, 3));
+
+        let first = &spanned[0];
+        assert_eq!((first.line, first.col), (1, 1));
+        let close = spanned.last().unwrap();
+        assert_eq!((close.token.clone(), close.line, close.col), (Token::RBrace, 3, 1));
+    }
+
+    #[test]
+    fn test_tokenizer_hex_and_octal() {
+        assert_eq!(tokens("0xFF"), vec![Token::Number(255)]);
+        assert_eq!(tokens("0x1f"), vec![Token::Number(31)]);
+        assert_eq!(tokens("0755"), vec![Token::Number(493)]);
+        assert_eq!(tokens("0"), vec![Token::Number(0)]);
+        assert_eq!(tokens("120"), vec![Token::Number(120)]);
+    }
+
+    #[test]
+    fn test_tokenizer_type_keywords() {
+        let ident = |s: &str| Token::Identifier(s.to_string());
+        assert_eq!(
+            tokens("char c; void f();"),
+            vec![
+                Token::Char,
+                ident("c"),
+                Token::Semicolon,
+                Token::Void,
+                ident("f"),
+                Token::LParen,
+                Token::RParen,
+                Token::Semicolon,
+            ]
+        );
+        //only whole words are keywords
+        assert_eq!(tokens("chars voids"), vec![ident("chars"), ident("voids")]);
+    }
+
+    #[test]
+    fn test_tokenizer_increment_and_compound_assign() {
+        let ident = |s: &str| Token::Identifier(s.to_string());
+        assert_eq!(tokens("i++;"), vec![ident("i"), Token::PlusPlus, Token::Semicolon]);
+        assert_eq!(tokens("--n"), vec![Token::MinusMinus, ident("n")]);
+        assert_eq!(tokens("x += 5"), vec![ident("x"), Token::PlusEq, Token::Number(5)]);
+        assert_eq!(
+            tokens("a -= b *= c /= d %= e"),
+            vec![
+                ident("a"),
+                Token::MinusEq,
+                ident("b"),
+                Token::StarEq,
+                ident("c"),
+                Token::DivEq,
+                ident("d"),
+                Token::ModEq,
+                ident("e"),
+            ]
+        );
+        //separated by a space they stay two single operators
+        assert_eq!(tokens("a + +b"), vec![ident("a"), Token::Plus, Token::Plus, ident("b")]);
+        assert_eq!(tokens("a - -b"), vec![ident("a"), Token::Minus, Token::Minus, ident("b")]);
+        assert_eq!(tokens("p->x"), vec![ident("p"), Token::Arrow, ident("x")]);
+    }
+
+    #[test]
+    fn test_tokenizer_logical_operators() {
+        let ident = |s: &str| Token::Identifier(s.to_string());
+        assert_eq!(tokens("a && b"), vec![ident("a"), Token::And, ident("b")]);
+        assert_eq!(tokens("a || b"), vec![ident("a"), Token::Or, ident("b")]);
+        //a single '&' or '|' is kept as its own token, not merged or dropped
+        assert_eq!(tokens("a & b"), vec![ident("a"), Token::BitAnd, ident("b")]);
+        assert_eq!(tokens("a|b"), vec![ident("a"), Token::BitOr, ident("b")]);
+        assert_eq!(tokens("&&&"), vec![Token::And, Token::BitAnd]);
+    }
+
+    #[test]
+    fn test_tokenizer_char_literals() {
+        assert_eq!(tokens("'A'"), vec![Token::CharLiteral(65)]);
+        assert_eq!(tokens("'\\n'"), vec![Token::CharLiteral(10)]);
+        assert_eq!(tokens("'\\''"), vec![Token::CharLiteral(39)]);
+
+        //a char constant is just a number to the rest of the compiler
+        let tokens = tokens("int main() { return 'a' == 97; }");
+        let mut vm = VM::new(crate::codegen::generate_instructions(&parse(&tokens)));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&1));
+    }
+
+    #[test]
+    #[should_panic(expected = "unterminated character literal")]
+    fn test_tokenizer_unterminated_char_literal() {
+        tokens("'ab");
+    }
+
+    #[test]
+    #[should_panic(expected = "struct member access not yet supported")]
+    fn test_parser_rejects_member_access() {
+        parse(&tokens("int main() { return p->x; }"));
+    }
+
     #[test]
     fn test_var_decl_and_return() {
         ///test variable declaration and return statement
-        use crate::lexer::tokenize;
+        use crate::lexer::tokens;
         use crate::parser::parse;
         use crate::codegen::generate_instructions;
         use crate::vm::VM;
 
-        let tokens = tokenize("int main() { int x = 5; return x; }");
+        let tokens = tokens("int main() { int x = 5; return x; }");
         let ast = parse(&tokens);
         let instructions = generate_instructions(&ast);
         let mut vm = VM::new(instructions);
-        vm.run();
+        vm.run_or_panic();
 
         assert_eq!(vm.stack.last(), Some(&5));
     }
 
     #[test]
-    #[should_panic(expected = "Unresolved call to add")]
+    fn test_codegen_fused_load() {
+        //variable reads use a single LOAD instead of LEA + LI
+        use crate::codegen::generate_instructions;
+
+        let tokens = tokens("int main() { int x = 3; int y = x * x; return x + y; }");
+        let ins = generate_instructions(&parse(&tokens));
+
+        let loads = ins.iter().filter(|i| matches!(i, Instruction::LOAD(_))).count();
+        assert_eq!(loads, 4);
+        assert!(!ins.contains(&Instruction::LI));
+        //the LEA + LI form would need one extra instruction per read
+        assert_eq!(ins.len(), 11);
+
+        let mut vm = VM::new(ins);
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&12));
+    }
+
+    #[test]
+    fn test_codegen_fused_store() {
+        //simple assignments store with a single STORE instead of LEA ... SI
+        use crate::codegen::generate_instructions;
+
+        let tokens = tokens("int main() { int x = 5; x = x + 2; return x; }");
+        let ins = generate_instructions(&parse(&tokens));
+
+        assert_eq!(
+            ins,
+            vec![
+                Instruction::ENT(1),
+                Instruction::IMM(5),
+                Instruction::STORE(0),
+                Instruction::LOAD(0),
+                Instruction::IMM(2),
+                Instruction::ADD,
+                Instruction::STORE(0),
+                Instruction::LOAD(0),
+                Instruction::EXIT,
+            ]
+        );
+
+        let mut vm = VM::new(ins);
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&7));
+    }
+
+    #[test]
     fn test_codegen_function_call() {
         use crate::codegen::{generate_instructions, ASTNode, Expr};
 
@@ -386,7 +1389,58 @@ mod tests {
             ))),
         ]);
 
-        //this should panic because codegen cannot resolve the 'add' address
+        //int add(int a, int b){ return a+b; } int main(){ return add(2,3); }
+        let program = generate_instructions(&ast);
+        assert!(program.contains(&Instruction::ADJ(2)));
+        let mut vm = VM::new(program);
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&5));
+    }
+
+    #[test]
+    fn test_vm_backtrace_names_frames() {
+        //int boom() { return 1 / 0; } main: return boom();
+        use crate::codegen::generate_program;
+
+        let ast = ASTNode::Sequence(vec![
+            ASTNode::FunctionDef {
+                name: "boom".to_string(),
+                params: vec![],
+                body: Box::new(ASTNode::Return(Box::new(Expr::Div(
+                    Box::new(Expr::Number(1)),
+                    Box::new(Expr::Number(0)),
+                )))),
+            },
+            ASTNode::Return(Box::new(Expr::Call("boom".to_string(), vec![]))),
+        ]);
+        let (program, functions) = generate_program(&ast);
+
+        let mut vm = VM::new(program);
+        vm.set_functions(functions);
+        assert_eq!(vm.run(), Err(VmError::DivByZero));
+
+        let names: Vec<String> = vm.backtrace().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["boom", "main"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unresolved functions: foo, bar")]
+    fn test_codegen_reports_all_unresolved_calls() {
+        //both missing names should be listed together, each only once
+        use crate::codegen::{generate_instructions, ASTNode, Expr};
+
+        let ast = ASTNode::Sequence(vec![
+            ASTNode::Declaration(
+                "x".to_string(),
+                Box::new(Expr::Call("foo".to_string(), vec![])),
+                Type::Int,
+            ),
+            ASTNode::Return(Box::new(Expr::Add(
+                Box::new(Expr::Call("bar".to_string(), vec![])),
+                Box::new(Expr::Call("foo".to_string(), vec![])),
+            ))),
+        ]);
+
         let _ = generate_instructions(&ast);
     }
 
@@ -396,11 +1450,11 @@ mod tests {
     fn test_parser_print_statement() {
         //test print statement parsing
         let src = r#"int main() { printf("hey\n"); return 0; }"#;
-        let tokens = tokenize(src);
+        let tokens = tokens(src);
         let ast = parse(&tokens);
         assert_eq!(
             ast,
-            ASTNode::Sequence(vec![
+            main_def(vec![
                 //printf("hey\n");
                 ASTNode::Print("hey\n".to_string()),
                 //return 0;
@@ -419,7 +1473,7 @@ mod tests {
             Instruction::EXIT,
         ];
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
         assert_eq!(vm.stack, vec![5]);
     }
 
@@ -433,7 +1487,7 @@ mod tests {
             Instruction::EXIT,
         ];
         let mut vm = VM::new(program);
-        vm.run();
+        vm.run_or_panic();
         assert_eq!(vm.stack, vec![1]);
     }
 
@@ -441,20 +1495,48 @@ mod tests {
     fn test_vm_comparisons() {
         // LT: 3 < 5 => 1
         let mut vm1 = VM::new(vec![Instruction::IMM(3), Instruction::IMM(5), Instruction::LT, Instruction::EXIT]);
-        vm1.run();
+        vm1.run_or_panic();
         assert_eq!(vm1.stack, vec![1]);
 
         // EQ: 5 == 5 => 1
         let mut vm2 = VM::new(vec![Instruction::IMM(5), Instruction::IMM(5), Instruction::EQ, Instruction::EXIT]);
-        vm2.run();
+        vm2.run_or_panic();
         assert_eq!(vm2.stack, vec![1]);
 
         // GT: 6 > 5 => 1
         let mut vm3 = VM::new(vec![Instruction::IMM(6), Instruction::IMM(5), Instruction::GT, Instruction::EXIT]);
-        vm3.run();
+        vm3.run_or_panic();
         assert_eq!(vm3.stack, vec![1]);
     }
 
+    #[test]
+    fn test_vm_neg() {
+        //NEG negates the top of the stack
+        let mut vm = VM::new(vec![Instruction::IMM(7), Instruction::NEG, Instruction::EXIT]);
+        vm.run_or_panic();
+        assert_eq!(vm.stack, vec![-7]);
+
+        //and wraps rather than overflowing on i64::MIN
+        let mut vm = VM::new(vec![Instruction::IMM(i64::MIN), Instruction::NEG, Instruction::EXIT]);
+        vm.run_or_panic();
+        assert_eq!(vm.stack, vec![i64::MIN]);
+    }
+
+    #[test]
+    fn test_vm_explain_listing() {
+        //the explained listing describes each opcode's stack effect
+        use crate::vm::disassemble;
+
+        let program = vec![Instruction::IMM(5), Instruction::IMM(2), Instruction::ADD, Instruction::EXIT];
+        let listing = disassemble(&program, true);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("IMM(5)") && lines[0].ends_with("; push 5"));
+        assert!(lines[2].contains("ADD") && lines[2].contains("pop b, pop a, push a+b"));
+        assert!(!disassemble(&program, false).contains(';'));
+    }
+
     #[test]
     fn test_codegen_print_instruction() {
         //ensure codegen emits a PrintfStr for Print nodes, then a return
@@ -470,21 +1552,67 @@ mod tests {
                 Instruction::ENT(0),
                 Instruction::PrintfStr("foo\n".to_string()),
                 Instruction::IMM(0),
-                Instruction::PSH,
                 Instruction::EXIT,
             ]
         );
     }
 
+    #[test]
+    fn test_codegen_literal_stats() {
+        //duplicated literals are only counted once
+        use crate::codegen::{generate_instructions, literal_stats, LiteralStats};
+
+        let src = r#"int main() { printf("hi\n"); printf("hello\n"); printf("hi\n"); return 0; }"#;
+        let ins = generate_instructions(&parse(&tokens(src)));
+
+        assert_eq!(literal_stats(&ins), LiteralStats { unique: 2, bytes: 9 });
+    }
+
+    #[test]
+    fn test_printf_with_arguments() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+
+        let src = r#"int main() { int x = 7; printf("%d\n", 2 + 3); printf("%d-%d%%\n", x, x * 2); return 0; }"#;
+        let ast = parse(&tokens(src));
+
+        let mut vm = VM::new(generate_instructions(&ast));
+        let (_, output) = vm.run_capturing().unwrap();
+        assert_eq!(output, "5\n7-14%\n");
+
+        //the IR path prints the same thing
+        let mut vm = VM::new(lower_ir(&build_ir(&ast)));
+        let (_, output) = vm.run_capturing().unwrap();
+        assert_eq!(output, "5\n7-14%\n");
+    }
+
+    #[test]
+    fn test_empty_main_exits_zero() {
+        //an empty body, or one that never returns, exits with 0
+        use crate::codegen::generate_instructions;
+
+        let ast = parse(&tokens("int main(){}"));
+        assert_eq!(ast, main_def(vec![]));
+        let mut vm = VM::new(generate_instructions(&ast));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&0));
+
+        let tokens = tokens(r#"int main() { printf("x"); }"#);
+        let mut vm = VM::new(generate_instructions(&parse(&tokens)));
+        let (exit_value, output) = vm.run_capturing().unwrap();
+        assert_eq!(exit_value, Some(0));
+        assert_eq!(output, "x");
+    }
+
     #[test]
     fn test_parser_division_and_modulo() {
         //verify parser handles 10 / 2 % 3 with correct AST structure
         use crate::codegen::{ASTNode, Expr};
-        let tokens = tokenize("int main() { return 10 / 2 % 3; }");
+        let tokens = tokens("int main() { return 10 / 2 % 3; }");
         let ast = parse(&tokens);
         assert_eq!(
             ast,
-            ASTNode::Sequence(vec![
+            main_def(vec![
                 ASTNode::Return(Box::new(Expr::Mod(
                     Box::new(Expr::Div(
                         Box::new(Expr::Number(10)),
@@ -496,32 +1624,719 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parser_comparisons() {
+        //comparisons bind looser than arithmetic, equality looser than relational
+        let ret = |src: &str| match parse(&tokens(src)) {
+            ASTNode::Sequence(mut funcs) => match funcs.remove(0) {
+                ASTNode::FunctionDef { body, .. } => match *body {
+                    ASTNode::Sequence(mut stmts) => stmts.remove(0),
+                    other => panic!("unexpected body {:?}", other),
+                },
+                other => panic!("unexpected AST {:?}", other),
+            },
+            other => panic!("unexpected AST {:?}", other),
+        };
+        let num = |n: i64| Box::new(Expr::Number(n));
+
+        assert_eq!(
+            ret("int main() { return 1 < 2; }"),
+            ASTNode::Return(Box::new(Expr::Less(num(1), num(2))))
+        );
+        assert_eq!(
+            ret("int main() { return 1 + 2 == 3; }"),
+            ASTNode::Return(Box::new(Expr::Equal(Box::new(Expr::Add(num(1), num(2))), num(3))))
+        );
+        assert_eq!(
+            ret("int main() { return 1 <= 2 != 3 >= 4; }"),
+            ASTNode::Return(Box::new(Expr::NotEqual(
+                Box::new(Expr::LessEqual(num(1), num(2))),
+                Box::new(Expr::GreaterEqual(num(3), num(4))),
+            )))
+        );
+    }
+
+    #[test]
+    fn test_comparisons_end_to_end() {
+        //each comparison evaluates to 0 or 1 in the VM
+        use crate::codegen::generate_instructions;
+
+        let run = |src: &str| {
+            let mut vm = VM::new(generate_instructions(&parse(&tokens(src))));
+            vm.run_or_panic();
+            vm.stack.last().copied()
+        };
+        assert_eq!(run("int main() { return 2 > 1; }"), Some(1));
+        assert_eq!(run("int main() { return 2 <= 1; }"), Some(0));
+        assert_eq!(run("int main() { return 3 >= 3; }"), Some(1));
+        assert_eq!(run("int main() { return 3 != 3; }"), Some(0));
+        assert_eq!(run("int main() { int x = 5; if (x == 5) { return 7; } return 8; }"), Some(7));
+    }
+
     #[test]
     fn test_parser_declaration_and_assignment() {
         use crate::codegen::{ASTNode, Expr};
-        use crate::lexer::tokenize;
+        use crate::lexer::tokens;
         use crate::parser::parse;
 
         let src = "int main() { int x = 5; x = 10; return x; }";
-        let tokens = tokenize(src);
+        let tokens = tokens(src);
         let ast = parse(&tokens);
 
         assert_eq!(
             ast,
-            ASTNode::Sequence(vec![
-                ASTNode::Declaration("x".to_string(), Box::new(Expr::Number(5))),
+            main_def(vec![
+                ASTNode::Declaration("x".to_string(), Box::new(Expr::Number(5)), Type::Int),
                 ASTNode::Assignment("x".to_string(), Box::new(Expr::Number(10))),
                 ASTNode::Return(Box::new(Expr::Var("x".to_string()))),
             ])
         );
     }
 
+    #[test]
+    fn test_parser_function_params() {
+        let ast = parse(&tokens("int add(int a, int b){ return a+b; } int main() { return add(2, 3); }"));
+        let ASTNode::Sequence(funcs) = &ast else { panic!("unexpected AST {:?}", ast) };
+        match &funcs[0] {
+            ASTNode::FunctionDef { name, params, .. } => {
+                assert_eq!(name, "add");
+                assert_eq!(params, &vec!["a".to_string(), "b".to_string()]);
+            }
+            other => panic!("expected FunctionDef, got {:?}", other),
+        }
+
+        //the whole program from source now runs through real calls
+        let mut vm = VM::new(crate::codegen::generate_instructions(&ast));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected ',' or ')' after parameter of add")]
+    fn test_parser_rejects_unclosed_params() {
+        parse(&tokens("int add(int a, int b { return a; }"));
+    }
+
+    #[test]
+    fn test_codegen_rejects_duplicate_params() {
+        use c4_rust_ghiyathi::{compile_full, CompileError};
+
+        let err = compile_full("int f(int a, int a) { return a; } int main() { return f(1, 2); }");
+        assert_eq!(
+            err,
+            Err(CompileError::Codegen("duplicate parameter 'a' in function f".to_string()))
+        );
+        assert!(compile_full("int f(int a, int b) { return a; } int main() { return f(1, 2); }").is_ok());
+    }
+
+    #[test]
+    fn test_declaration_without_initializer() {
+        use crate::codegen::generate_instructions;
+
+        let ast = parse(&tokens("int main() { int x; x = 7; return x; }"));
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Declaration("x".to_string(), Box::new(Expr::Number(0)), Type::Int),
+                ASTNode::Assignment("x".to_string(), Box::new(Expr::Number(7))),
+                ASTNode::Return(Box::new(Expr::Var("x".to_string()))),
+            ])
+        );
+
+        let mut vm = VM::new(generate_instructions(&ast));
+        assert_eq!(vm.run(), Ok(7));
+    }
+
+    #[test]
+    fn test_char_locals_store_a_byte() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+        use crate::opt::optimize;
+
+        let ast = parse(&tokens("int main() { char c = 'A'; int n = 300; char *p = 0; return c; }"));
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Declaration("c".to_string(), Box::new(Expr::Number(65)), Type::Char),
+                ASTNode::Declaration("n".to_string(), Box::new(Expr::Number(300)), Type::Int),
+                ASTNode::Declaration("p".to_string(), Box::new(Expr::Number(0)), Type::Ptr),
+                ASTNode::Return(Box::new(Expr::Var("c".to_string()))),
+            ])
+        );
+        let ins = generate_instructions(&ast);
+        assert!(ins.contains(&Instruction::SC) && ins.contains(&Instruction::LC));
+        assert_eq!(VM::new(ins).run(), Ok(65));
+
+        //a char keeps only the low byte, an int keeps the whole value
+        let src = "int main() { char c = 300; int n = 300; c = c + 256; return c * 1000 + n; }";
+        let ast = parse(&tokens(src));
+        assert_eq!(VM::new(generate_instructions(&ast)).run(), Ok(44_300));
+        assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), Ok(44_300));
+        assert_eq!(VM::new(generate_instructions(&optimize(&ast))).run(), Ok(44_300));
+    }
+
+    #[test]
+    fn test_parser_multiple_declarators() {
+        use crate::codegen::generate_instructions;
+
+        let ast = parse(&tokens("int main() { int a = 1, b = 2; return a * 10 + b; }"));
+        let num = |n: i64| Box::new(Expr::Number(n));
+        let var = |s: &str| Box::new(Expr::Var(s.to_string()));
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Sequence(vec![
+                    ASTNode::Declaration("a".to_string(), num(1), Type::Int),
+                    ASTNode::Declaration("b".to_string(), num(2), Type::Int),
+                ]),
+                ASTNode::Return(Box::new(Expr::Add(Box::new(Expr::Mul(var("a"), num(10))), var("b")))),
+            ])
+        );
+
+        let mut vm = VM::new(generate_instructions(&ast));
+        assert_eq!(vm.run(), Ok(12));
+
+        //declarators without initializers mix with ones that have them
+        let ast = parse(&tokens("int main() { int i = 3, n, k = i + 1; n = 5; return i + n * k; }"));
+        let mut vm = VM::new(generate_instructions(&ast));
+        assert_eq!(vm.run(), Ok(23));
+    }
+
+    #[test]
+    fn test_parser_unary_minus_and_not() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+
+        let ret = |src: &str| match parse(&tokens(&format!("int main() {{ int x = 0; int b = 2; return {}; }}", src))) {
+            ASTNode::Sequence(funcs) => match &funcs[0] {
+                ASTNode::FunctionDef { body, .. } => match body.as_ref() {
+                    ASTNode::Sequence(stmts) => stmts[2].clone(),
+                    other => panic!("unexpected body {:?}", other),
+                },
+                other => panic!("unexpected node {:?}", other),
+            },
+            other => panic!("unexpected AST {:?}", other),
+        };
+        let num = |n: i64| Box::new(Expr::Number(n));
+        let var = |s: &str| Box::new(Expr::Var(s.to_string()));
+
+        assert_eq!(ret("-5"), ASTNode::Return(Box::new(Expr::Neg(num(5)))));
+        assert_eq!(ret("-(1+2)"), ASTNode::Return(Box::new(Expr::Neg(Box::new(Expr::Add(num(1), num(2)))))));
+        assert_eq!(ret("!x"), ASTNode::Return(Box::new(Expr::Not(var("x")))));
+        //unary operators bind tighter than '*'
+        assert_eq!(ret("-x * b"), ASTNode::Return(Box::new(Expr::Mul(Box::new(Expr::Neg(var("x"))), var("b")))));
+
+        let run = |src: &str| {
+            let ast = parse(&tokens(src));
+            let direct = VM::new(generate_instructions(&ast)).run();
+            assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), direct, "{}", src);
+            direct
+        };
+        assert_eq!(run("int main() { int a = 4; return -a * 3 - -2; }"), Ok(-10));
+        assert_eq!(run("int main() { int done = 0; if (!done) return 1; return 2; }"), Ok(1));
+        assert_eq!(run("int main() { return !7 + !!7; }"), Ok(1));
+    }
+
+    #[test]
+    fn test_block_scopes_shadow_outer_variables() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+        use crate::opt::optimize;
+
+        //the inner x is a separate local, the outer one is visible again after the block
+        let src = "int main() { int x = 1; int y = 0; if (x) { int x = 5; y = x; } return x * 10 + y; }";
+        let ast = parse(&tokens(src));
+        assert_eq!(VM::new(generate_instructions(&ast)).run(), Ok(15));
+        assert_eq!(VM::new(generate_instructions(&optimize(&ast))).run(), Ok(15));
+        assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), Ok(15));
+        //both x's get their own slot in main's frame
+        assert_eq!(generate_instructions(&ast)[0], Instruction::ENT(3));
+
+        //assignments inside a block still reach the outer variable
+        let src = "int main() { int x = 1; { x = 2; } return x; }";
+        assert_eq!(VM::new(generate_instructions(&parse(&tokens(src)))).run(), Ok(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "Use of undeclared variable: y")]
+    fn test_block_variables_are_not_visible_outside() {
+        use crate::codegen::generate_instructions;
+
+        generate_instructions(&parse(&tokens("int main() { { int y = 1; } return y; }")));
+    }
+
+    #[test]
+    fn test_program_of_only_function_defs() {
+        //main calling another function must run both bodies, not compile to a bare exit
+        use crate::codegen::generate_program;
+
+        let ast = parse(&tokens("int seven() { return 7; } int main() { return seven() * 6; }"));
+        let (ins, functions) = generate_program(&ast);
+        assert_eq!(VM::new(ins).run(), Ok(42));
+        assert_eq!(functions.iter().map(|f| f.0.as_str()).collect::<Vec<_>>(), vec!["main", "seven"]);
+
+        //without a main the functions are still compiled behind an entry that exits with 0
+        let (ins, functions) = generate_program(&parse(&tokens("int seven() { return 7; }")));
+        assert!(ins.contains(&Instruction::IMM(7)));
+        assert_eq!(functions.len(), 2);
+        assert_eq!(VM::new(ins).run(), Ok(0));
+    }
+
+    #[test]
+    fn test_parser_call_statement() {
+        //a bare call is an expression statement, not a malformed assignment
+        use crate::codegen::{generate_instructions, ASTNode, Expr};
+
+        let ast = parse(&tokens("int main() { printf_like(); return 0; }"));
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::ExprStmt(Box::new(Expr::Call("printf_like".to_string(), vec![]))),
+                ASTNode::Return(Box::new(Expr::Number(0))),
+            ])
+        );
+
+        //the call runs for its output and its result is dropped from the stack
+        let src = "int f(int n) { printf(\"f %d\\n\", n); return 9; } int main() { f(4); f(5); return 1; }";
+        let mut vm = VM::new(generate_instructions(&parse(&tokens(src))));
+        assert_eq!(vm.run_capturing().unwrap(), (Some(1), "f 4\nf 5\n".to_string()));
+        assert_eq!(vm.stack, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        use crate::codegen::generate_instructions;
+
+        let var = |s: &str| Box::new(Expr::Var(s.to_string()));
+        let ast = parse(&tokens("int main() { int x = 1; x += 3; }"));
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Declaration("x".to_string(), Box::new(Expr::Number(1)), Type::Int),
+                ASTNode::Assignment("x".to_string(), Box::new(Expr::Add(var("x"), Box::new(Expr::Number(3))))),
+            ])
+        );
+
+        let run = |src: &str| VM::new(generate_instructions(&parse(&tokens(src)))).run();
+        assert_eq!(run("int main() { int x = 10; x -= 4; return x; }"), Ok(6));
+        assert_eq!(run("int main() { int x = 7; x *= 6; x /= 4; x %= 8; return x; }"), Ok(2));
+        //the for-loop step goes through the same path
+        assert_eq!(run("int main() { int s = 0; for (int i = 0; i < 10; i += 3) s += i; return s; }"), Ok(18));
+    }
+
+    #[test]
+    fn test_increment_statements() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+
+        let ast = parse(&tokens("int main() { int i = 0; i++; ++i; }"));
+        let incr = ASTNode::Assignment(
+            "i".to_string(),
+            Box::new(Expr::Add(Box::new(Expr::Var("i".to_string())), Box::new(Expr::Number(1)))),
+        );
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Declaration("i".to_string(), Box::new(Expr::Number(0)), Type::Int),
+                incr.clone(),
+                incr,
+            ])
+        );
+
+        let run = |src: &str| {
+            let ast = parse(&tokens(src));
+            let direct = VM::new(generate_instructions(&ast)).run();
+            assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), direct, "{}", src);
+            direct
+        };
+        assert_eq!(run("int main() { int i = 0; while (i < 5) { i++; } return i; }"), Ok(5));
+        assert_eq!(run("int main() { int n = 9; --n; n--; return n; }"), Ok(7));
+        assert_eq!(run("int main() { int s = 0; for (int i = 0; i < 4; ++i) s += i; return s; }"), Ok(6));
+    }
+
+    #[test]
+    fn test_break_and_continue() {
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+        use crate::opt::optimize;
+
+        let run = |src: &str| {
+            let ast = parse(&tokens(src));
+            let direct = VM::new(generate_instructions(&ast)).run();
+            assert_eq!(VM::new(generate_instructions(&optimize(&ast))).run(), direct, "{}", src);
+            assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), direct, "{}", src);
+            direct
+        };
+        //stops as soon as the counter hits 3
+        assert_eq!(run("int main() { int i = 0; while (1) { if (i == 3) break; i = i + 1; } return i; }"), Ok(3));
+        //continue in a for loop still runs the step: sums the odd numbers below 10
+        let src = "int main() { int s = 0; for (int i = 0; i < 10; i = i + 1) { if (i % 2 == 0) continue; s = s + i; } return s; }";
+        assert_eq!(run(src), Ok(25));
+        //break only leaves the innermost loop
+        let src = "int main() { int n = 0; int i = 0; while (i < 3) { while (1) { n = n + 1; break; } i = i + 1; } return n; }";
+        assert_eq!(run(src), Ok(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "'break' outside of a loop")]
+    fn test_break_outside_loop_is_rejected() {
+        use crate::codegen::generate_instructions;
+
+        generate_instructions(&parse(&tokens("int main() { if (1) break; return 0; }")));
+    }
+
+    #[test]
+    fn test_parser_for_loop() {
+        //for (i = 0; i < 3; i = i + 1) x = x + i;  becomes  { i = 0; while (i < 3) x = x + i; with step i = i + 1 }
+        let ast = parse(&tokens("int main() { int x = 0; int i = 0; for (i = 0; i < 3; i = i + 1) x = x + i; return x; }"));
+        let var = |s: &str| Box::new(Expr::Var(s.to_string()));
+        let num = |n: i64| Box::new(Expr::Number(n));
+        assert_eq!(
+            ast,
+            main_def(vec![
+                ASTNode::Declaration("x".to_string(), num(0), Type::Int),
+                ASTNode::Declaration("i".to_string(), num(0), Type::Int),
+                ASTNode::Block(vec![
+                    ASTNode::Assignment("i".to_string(), num(0)),
+                    ASTNode::While {
+                        condition: Box::new(Expr::Less(var("i"), num(3))),
+                        body: Box::new(ASTNode::Assignment("x".to_string(), Box::new(Expr::Add(var("x"), var("i"))))),
+                        step: Some(Box::new(ASTNode::Assignment(
+                            "i".to_string(),
+                            Box::new(Expr::Add(var("i"), num(1))),
+                        ))),
+                    },
+                ]),
+                ASTNode::Return(var("x")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_for_loop_sums_end_to_end() {
+        use crate::codegen::generate_instructions;
+
+        let src = "int main() { int sum = 0; for (int i = 0; i < 5; i = i + 1) { sum = sum + i; } return sum; }";
+        let mut vm = VM::new(generate_instructions(&parse(&tokens(src))));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&10));
+    }
+
+    #[test]
+    fn test_codegen_function_sizes_sum_to_total() {
+        use crate::codegen::{function_sizes, generate_program};
+
+        let src = "int sq(int x) { return x * x; } int main() { return sq(3); }";
+        let (program, functions) = generate_program(&parse(&tokens(src)));
+        let sizes = function_sizes(&program, &functions);
+        let names: Vec<&str> = sizes.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["main", "sq"]);
+        assert!(sizes.iter().all(|(_, size)| *size > 0));
+        assert_eq!(sizes.iter().map(|(_, size)| size).sum::<usize>(), program.len());
+    }
+
+    #[test]
+    fn test_parser_multiple_functions() {
+        //helpers before main are kept, main is an ordinary FunctionDef
+        let ast = parse(&tokens("int seven() { return 7; } int main() { return 1; }"));
+        let names: Vec<&str> = match &ast {
+            ASTNode::Sequence(funcs) => funcs
+                .iter()
+                .map(|f| match f {
+                    ASTNode::FunctionDef { name, .. } => name.as_str(),
+                    other => panic!("expected FunctionDef, got {:?}", other),
+                })
+                .collect(),
+            other => panic!("unexpected AST {:?}", other),
+        };
+        assert_eq!(names, vec!["seven", "main"]);
+
+        let mut vm = VM::new(crate::codegen::generate_instructions(&parse(&tokens(
+            "int seven() { return 7; } int main() { return seven() * 2; }",
+        ))));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&14));
+    }
+
+    #[test]
+    #[should_panic(expected = "nested too deeply")]
+    fn test_parser_rejects_deep_nesting() {
+        //10,000 nested parens must be reported, not overflow the stack
+        use crate::parser::{check_nesting, ParseError, DEFAULT_MAX_NESTING};
+
+        //moderate nesting is still fine
+        let src = format!("int main() {{ return {}1{}; }}", "(".repeat(50), ")".repeat(50));
+        assert_eq!(check_nesting(&tokens(&src), DEFAULT_MAX_NESTING), Ok(()));
+
+        let src = format!("int main() {{ return {}1{}; }}", "(".repeat(10_000), ")".repeat(10_000));
+        let tokens = tokens(&src);
+        assert_eq!(
+            check_nesting(&tokens, DEFAULT_MAX_NESTING),
+            Err(ParseError::TooDeep { limit: DEFAULT_MAX_NESTING })
+        );
+        parse(&tokens);
+    }
+
+    #[test]
+    fn test_parser_rejects_long_operator_chains() {
+        //prefix operators recurse in parse_unary, binary chains in emit_expr and drop,
+        //so 100,000 of either must be reported instead of overflowing the stack
+        use crate::parser::{check_nesting, ParseError, DEFAULT_MAX_NESTING};
+
+        let limit = DEFAULT_MAX_NESTING;
+        let too_deep = Err(ParseError::TooDeep { limit });
+        let src = format!("int main() {{ return {}1; }}", "~".repeat(100_000));
+        assert_eq!(check_nesting(&tokens(&src), limit), too_deep);
+        let src = format!("int main() {{ return 1{}; }}", "+1".repeat(100_000));
+        assert_eq!(check_nesting(&tokens(&src), limit), too_deep);
+        let src = format!("int main() {{ return -({}1); }}", "!-".repeat(50_000));
+        assert_eq!(check_nesting(&tokens(&src), limit), too_deep);
+        match c4_rust_ghiyathi::compile_full(&src) {
+            Err(c4_rust_ghiyathi::CompileError::Parse(msg)) => assert!(msg.contains("nested too deeply"), "{}", msg),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        //the count starts over with each statement, so many short ones are fine
+        let src = format!("int main() {{ int x = 0; {} return x + 1 + 1; }}", "x = x + 1;".repeat(1_000));
+        assert_eq!(check_nesting(&tokens(&src), limit), Ok(()));
+        //and a flat 300-term chain compiles and runs
+        let src = format!("int main() {{ return 1{}; }}", "+1".repeat(299));
+        let program = c4_rust_ghiyathi::compile_full(&src).unwrap().instructions;
+        assert_eq!(VM::new(program).run(), Ok(300));
+    }
+
+    #[test]
+    fn test_try_parse_returns_nesting_errors() {
+        use crate::parser::{try_parse, ParseError};
+
+        //a caller-chosen limit is reported as an error instead of a panic
+        let src = format!("int main() {{ return {}1{}; }}", "(".repeat(20), ")".repeat(20));
+        assert_eq!(try_parse(&tokens(&src), 10), Err(ParseError::TooDeep { limit: 10 }));
+        assert!(try_parse(&tokens(&src), 30).is_ok());
+    }
+
+    #[test]
+    fn test_opt_constant_propagation() {
+        //int x = 5; return x + 1; folds the return to a single IMM(6)
+        use crate::codegen::generate_instructions;
+        use crate::opt::optimize;
+
+        let ast = optimize(&parse(&tokens("int main() { int x = 5; return x + 1; }")));
+        let ins = generate_instructions(&ast);
+
+        assert_eq!(
+            ins,
+            vec![
+                Instruction::ENT(1),
+                Instruction::IMM(5),
+                Instruction::STORE(0),
+                Instruction::IMM(6),
+                Instruction::EXIT,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        //the right operands divide by zero, so they must never run
+        use crate::codegen::generate_instructions;
+        use crate::ir::{build_ir, lower_ir};
+
+        let run = |src: &str| {
+            let ast = parse(&tokens(src));
+            let direct = VM::new(generate_instructions(&ast)).run();
+            assert_eq!(VM::new(lower_ir(&build_ir(&ast))).run(), direct, "{}", src);
+            direct
+        };
+        assert_eq!(run("int main() { int x = 0; return x != 0 && 10 / x > 1; }"), Ok(0));
+        assert_eq!(run("int main() { int x = 0; return x == 0 || 10 / x; }"), Ok(1));
+        assert_eq!(run("int main() { int x = 0; if (x && 1 / x) return 5; return 6; }"), Ok(6));
+        //when the left side does not decide, the right side is evaluated
+        assert_eq!(run("int main() { int x = 2; return x > 1 && x < 3; }"), Ok(1));
+        assert_eq!(run("int main() { int x = 2; return x < 1 || x > 3; }"), Ok(0));
+        assert_eq!(run("int main() { int x = 0; return 1 && 10 / x; }"), Err(VmError::DivByZero));
+        //&& binds tighter than ||
+        assert_eq!(run("int main() { return 1 || 0 && 0; }"), Ok(1));
+    }
+
+    #[test]
+    fn test_opt_folds_bitwise_not() {
+        //~0 is computed at compile time instead of by a runtime NOT
+        use crate::codegen::generate_instructions;
+        use crate::opt::optimize;
+
+        let ast = parse(&tokens("int main() { return ~0; }"));
+        let mut vm = VM::new(generate_instructions(&ast));
+        assert!(vm.program.contains(&Instruction::BNOT));
+        assert_eq!(vm.run(), Ok(-1));
+
+        let ins = generate_instructions(&optimize(&ast));
+        assert_eq!(ins, vec![Instruction::ENT(0), Instruction::IMM(-1), Instruction::EXIT]);
+        let mut vm = VM::new(ins);
+        assert_eq!(vm.run(), Ok(-1));
+    }
+
+    #[test]
+    fn test_opt_stops_at_loop_reassignment() {
+        //x changes inside the loop, so its initializer must not be propagated
+        use crate::codegen::generate_instructions;
+        use crate::opt::optimize;
+
+        let ast = optimize(&parse(&tokens("int main() { int x = 3; while (x) x = x - 1; return x + 1; }")));
+        let ins = generate_instructions(&ast);
+
+        assert!(!ins.contains(&Instruction::IMM(4)));
+        let mut vm = VM::new(ins);
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&1));
+    }
+
+    #[test]
+    fn test_ir_temps_and_lowering() {
+        //a + b * c evaluates operands into temps, multiplication first
+        use crate::ir::{build_ir, lower_ir};
+
+        let src = "int main() { int a = 2; int b = 3; int c = 4; return a + b * c; }";
+        let ir = build_ir(&parse(&tokens(src)));
+        let dump = ir.to_string();
+        let tail: Vec<&str> = dump.lines().rev().take(6).collect::<Vec<_>>().into_iter().rev().collect();
+
+        assert_eq!(
+            tail,
+            vec![
+                "    t3 = a",
+                "    t4 = b",
+                "    t5 = c",
+                "    t6 = t4 * t5",
+                "    t7 = t3 + t6",
+                "    return t7",
+            ]
+        );
+
+        let mut vm = VM::new(lower_ir(&ir));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&14));
+    }
+
+    #[test]
+    fn test_try_build_ir_reports_unsupported() {
+        //functions and calls come back as errors the CLI can print instead of panics
+        use crate::ir::try_build_ir;
+
+        let ast = parse(&tokens("int sq(int x) { return x * x; } int main() { return sq(3); }"));
+        assert_eq!(try_build_ir(&ast).unwrap_err(), "IR does not support function definitions yet: sq");
+        let ast = parse(&tokens("int main() { return f(3) + g(); }"));
+        assert_eq!(try_build_ir(&ast).unwrap_err(), "IR does not support calls yet: f");
+        assert!(try_build_ir(&parse(&tokens("int main() { return 3; }"))).is_ok());
+    }
+
+    #[test]
+    fn test_ir_lowering_control_flow() {
+        //branches and loops lowered from the IR behave like direct codegen
+        use crate::ir::{build_ir, lower_ir};
+
+        //int i = 0; int s = 0; while (i < 5) { s = s + i; i = i + 1; }
+        //if (s > 9) return s; return 0;
+        let var = |n: &str| Box::new(Expr::Var(n.to_string()));
+        let num = |n: i64| Box::new(Expr::Number(n));
+        let ast = ASTNode::Sequence(vec![
+            ASTNode::Declaration("i".to_string(), num(0), Type::Int),
+            ASTNode::Declaration("s".to_string(), num(0), Type::Int),
+            ASTNode::While {
+                condition: Box::new(Expr::Less(var("i"), num(5))),
+                body: Box::new(ASTNode::Sequence(vec![
+                    ASTNode::Assignment("s".to_string(), Box::new(Expr::Add(var("s"), var("i")))),
+                    ASTNode::Assignment("i".to_string(), Box::new(Expr::Add(var("i"), num(1)))),
+                ])),
+                step: None,
+            },
+            ASTNode::If {
+                condition: Box::new(Expr::Greater(var("s"), num(9))),
+                then_branch: Box::new(ASTNode::Return(var("s"))),
+                else_branch: None,
+            },
+            ASTNode::Return(num(0)),
+        ]);
+        let ir = build_ir(&ast);
+
+        let mut vm = VM::new(lower_ir(&ir));
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&10));
+    }
+
+    #[test]
+    fn test_compile_full_returns_every_stage() {
+        use c4_rust_ghiyathi::{compile_full, CompileError};
+
+        let result = compile_full("int main() { int x = 2; return x * 3; }").unwrap();
+        assert!(!result.tokens.is_empty());
+        assert!(matches!(result.ast, ASTNode::Sequence(_)));
+        assert_eq!(result.instructions.last(), Some(&Instruction::EXIT));
+
+        //a stage failure comes back as an error naming that stage
+        let err = compile_full("int x;").unwrap_err();
+        assert!(matches!(err, CompileError::Parse(_)));
+    }
+
+    #[test]
+    fn test_golden_programs() {
+        //every tests/programs/NAME.c must print NAME.expected: its stdout, then "exit: <value>"
+        use c4_rust_ghiyathi::compile_full;
+        use std::path::Path;
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(&dir).expect("missing tests/programs") {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|e| e.to_str()) != Some("c") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let expected = std::fs::read_to_string(path.with_extension("expected"))
+                .unwrap_or_else(|_| panic!("no .expected file for {}", path.display()));
+
+            let compiled = compile_full(&source)
+                .unwrap_or_else(|e| panic!("{} failed to compile: {}", path.display(), e));
+            let mut vm = VM::new(compiled.instructions);
+            let (exit_value, output) = vm.run_capturing().unwrap();
+            let actual = format!("{}exit: {}\n", output, exit_value.unwrap_or(0));
+
+            assert_eq!(actual, expected, "output of {}", path.display());
+            checked += 1;
+        }
+        assert!(checked >= 2, "expected golden programs in {}", dir.display());
+    }
+
+    #[test]
+    fn test_batch_summary_counts_failures() {
+        use c4_rust_ghiyathi::{batch_summary, run_batch};
+
+        let programs = vec![
+            ("ok.c".to_string(), r#"int main() { printf("hi\n"); return 3; }"#.to_string()),
+            ("div.c".to_string(), r#"int main() { int x = 0; printf("start\n"); return 1 / x; }"#.to_string()),
+        ];
+        let entries = run_batch(&programs);
+
+        assert_eq!(entries[0].result, Ok(3));
+        assert_eq!(entries[0].output, "hi\n");
+        assert!(entries[0].passed());
+        assert!(!entries[1].passed());
+        assert!(entries[1].result.as_ref().unwrap_err().contains("division by zero"));
+        //what it printed before failing is kept
+        assert_eq!(entries[1].output, "start\n");
+
+        let summary = batch_summary(&entries);
+        assert!(summary.lines().any(|l| l.starts_with("ok.c") && l.contains("pass")));
+        assert!(summary.lines().any(|l| l.starts_with("div.c") && l.contains("FAIL")));
+        assert!(summary.ends_with("2 programs, 1 passed, 1 failed\n"));
+    }
+
     use crate::Cli;
 
     #[test]
     fn test_cli_parse_no_flags() {
         //only program name + input
-        let cli = Cli::parse_from(&["c4rust", "foo.c"]);
+        let cli = Cli::parse_from(["c4rust", "foo.c"]);
         assert!(!cli.tokens);
         assert!(!cli.ast);
         assert!(!cli.trace);
@@ -531,7 +2346,7 @@ mod tests {
     #[test]
     fn test_cli_parse_tokens_flag() {
         // --tokens should flip only the tokens flag
-        let cli = Cli::parse_from(&["c4rust", "--tokens", "foo.c"]);
+        let cli = Cli::parse_from(["c4rust", "--tokens", "foo.c"]);
         assert!(cli.tokens);
         assert!(!cli.ast);
         assert!(!cli.trace);
@@ -541,7 +2356,7 @@ mod tests {
     #[test]
     fn test_cli_parse_ast_flag() {
         // --ast should flip only the ast flag
-        let cli = Cli::parse_from(&["c4rust", "--ast", "foo.c"]);
+        let cli = Cli::parse_from(["c4rust", "--ast", "foo.c"]);
         assert!(!cli.tokens);
         assert!(cli.ast);
         assert!(!cli.trace);
@@ -551,7 +2366,7 @@ mod tests {
     #[test]
     fn test_cli_parse_trace_flag() {
         // --trace should flip only the trace flag
-        let cli = Cli::parse_from(&["c4rust", "--trace", "foo.c"]);
+        let cli = Cli::parse_from(["c4rust", "--trace", "foo.c"]);
         assert!(!cli.tokens);
         assert!(!cli.ast);
         assert!(cli.trace);
@@ -560,12 +2375,58 @@ mod tests {
 
     #[test]
     fn test_cli_parse_all_flags() {
-        let cli = Cli::parse_from(&["c4rust", "--trace", "--tokens", "--ast", "foo.c"]);
+        let cli = Cli::parse_from(["c4rust", "--trace", "--tokens", "--ast", "foo.c"]);
         assert!(cli.tokens);
         assert!(cli.ast);
         assert!(cli.trace);
         assert_eq!(cli.input, "foo.c");
     }
 
+    #[test]
+    fn test_cli_parse_output_buffering() {
+        use crate::vm::OutputBuffering;
+
+        let cli = Cli::parse_from(["c4rust", "foo.c"]);
+        assert_eq!(cli.output_buffering, OutputBuffering::Line);
+
+        let cli = Cli::parse_from(["c4rust", "--output-buffering=none", "foo.c"]);
+        assert_eq!(cli.output_buffering, OutputBuffering::None);
+
+        assert!(Cli::try_parse_from(["c4rust", "--output-buffering=bogus", "foo.c"]).is_err());
+    }
+
+    #[test]
+    fn test_ast_json_output() {
+        use crate::codegen::{ast_to_json, AstFormat};
+
+        let cli = Cli::parse_from(["c4rust", "--ast", "--ast-format=json", "foo.c"]);
+        assert_eq!(cli.ast_format, AstFormat::Json);
+        assert_eq!(Cli::parse_from(["c4rust", "foo.c"]).ast_format, AstFormat::Debug);
+
+        let json = ast_to_json(&parse(&tokens("int main() { return 2 + 3; }")));
+        assert!(json.contains(r#"{"Return":{"Add":[{"Number":2},{"Number":3}]}}"#));
+        assert!(json.starts_with(r#"{"Sequence":[{"FunctionDef":{"name":"main","params":[],"#));
+
+        let json = ast_to_json(&ASTNode::Print("say \"hi\"\n".to_string()));
+        assert_eq!(json, r#"{"Print":"say \"hi\"\n"}"#);
+    }
+
+    #[test]
+    fn test_cli_clock_drives_time_builtin() {
+        use crate::codegen::generate_instructions;
+        use crate::vm::Clock;
+
+        let cli = Cli::parse_from(["c4rust", "--clock", "100", "foo.c"]);
+        assert_eq!(cli.vm_clock(), Clock::Fixed(100));
+        let cli_real = Cli::parse_from(["c4rust", "--real-clock", "foo.c"]);
+        assert_eq!(cli_real.vm_clock(), Clock::Real);
+
+        let ast = parse(&tokens("int main() { return time(); }"));
+        let mut vm = VM::new(generate_instructions(&ast));
+        vm.set_clock(cli.vm_clock());
+        vm.run_or_panic();
+        assert_eq!(vm.stack.last(), Some(&100));
+    }
+
 
 }
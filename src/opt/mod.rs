@@ -0,0 +1,218 @@
+use crate::codegen::{ASTNode, Expr, Type};
+use std::collections::{HashMap, HashSet};
+
+///what is known about the variables at some point in the program
+#[derive(Debug, Clone, Default)]
+struct ConstEnv {
+    values: HashMap<String, i64>, //variables currently known to hold a constant value
+    chars: HashSet<String>,       //char variables, which only keep the low byte of what they are given
+}
+
+///optimizes a parsed program before codegen (enabled with --opt)
+///folds constant subexpressions and propagates constant initializers into later uses
+pub fn optimize(ast: &ASTNode) -> ASTNode {
+    let mut env = ConstEnv::default();
+    optimize_stmt(ast, &mut env)
+}
+
+///folds operators whose operands are all literals, e.g. 2 * 3 + 1 becomes 7
+pub fn fold_constants(expr: &Expr) -> Expr {
+    fold_expr(expr, &ConstEnv::default())
+}
+
+//rewrites a statement, updating env with what is known after it runs
+fn optimize_stmt(ast: &ASTNode, env: &mut ConstEnv) -> ASTNode {
+    match ast {
+        ASTNode::Sequence(stmts) => {
+            ASTNode::Sequence(stmts.iter().map(|s| optimize_stmt(s, env)).collect())
+        }
+        ASTNode::Block(stmts) => {
+            //inner declarations may shadow outer names, so keep what the block learned
+            //to itself and forget anything it touched once it ends
+            let mut inner = env.clone();
+            let block = ASTNode::Block(stmts.iter().map(|s| optimize_stmt(s, &mut inner)).collect());
+            forget_written(ast, env);
+            block
+        }
+        ASTNode::Declaration(name, expr, ty) => {
+            let value = fold_expr(expr, env);
+            if *ty == Type::Char {
+                env.chars.insert(name.clone());
+            } else {
+                env.chars.remove(name);
+            }
+            record(env, name, &value);
+            ASTNode::Declaration(name.clone(), Box::new(value), *ty)
+        }
+        ASTNode::Assignment(name, expr) => {
+            let value = fold_expr(expr, env);
+            record(env, name, &value);
+            ASTNode::Assignment(name.clone(), Box::new(value))
+        }
+        ASTNode::Return(expr) => ASTNode::Return(Box::new(fold_expr(expr, env))),
+        ASTNode::ExprStmt(expr) => ASTNode::ExprStmt(Box::new(fold_expr(expr, env))),
+        ASTNode::Print(s) => ASTNode::Print(s.clone()),
+        ASTNode::Printf { fmt, args } => ASTNode::Printf {
+            fmt: fmt.clone(),
+            args: args.iter().map(|a| fold_expr(a, env)).collect(),
+        },
+        ASTNode::If { condition, then_branch, else_branch } => {
+            let condition = fold_expr(condition, env);
+            let then_branch = optimize_stmt(then_branch, &mut env.clone());
+            let else_branch = else_branch
+                .as_ref()
+                .map(|e| Box::new(optimize_stmt(e, &mut env.clone())));
+            //either branch may have run, so forget anything they wrote
+            forget_written(ast, env);
+            ASTNode::If {
+                condition: Box::new(condition),
+                then_branch: Box::new(then_branch),
+                else_branch,
+            }
+        }
+        ASTNode::While { condition, body, step } => {
+            //the condition and body see values from any iteration, so drop those first
+            forget_written(ast, env);
+            let condition = fold_expr(condition, env);
+            let body = optimize_stmt(body, &mut env.clone());
+            let step = step.as_ref().map(|s| Box::new(optimize_stmt(s, &mut env.clone())));
+            ASTNode::While { condition: Box::new(condition), body: Box::new(body), step }
+        }
+        ASTNode::Break => ASTNode::Break,
+        ASTNode::Continue => ASTNode::Continue,
+        ASTNode::FunctionDef { name, params, body } => ASTNode::FunctionDef {
+            name: name.clone(),
+            params: params.clone(),
+            body: Box::new(optimize_stmt(body, &mut ConstEnv::default())),
+        },
+    }
+}
+
+//remembers the variable's value if it is now a known constant
+fn record(env: &mut ConstEnv, name: &str, value: &Expr) {
+    match value {
+        Expr::Number(n) if env.chars.contains(name) => env.values.insert(name.to_string(), n & 0xFF),
+        Expr::Number(n) => env.values.insert(name.to_string(), *n),
+        _ => env.values.remove(name),
+    };
+}
+
+//removes every variable declared or assigned anywhere inside the statement
+fn forget_written(ast: &ASTNode, env: &mut ConstEnv) {
+    match ast {
+        ASTNode::Declaration(name, ..) | ASTNode::Assignment(name, _) => {
+            env.values.remove(name);
+        }
+        ASTNode::Sequence(stmts) | ASTNode::Block(stmts) => {
+            stmts.iter().for_each(|s| forget_written(s, env))
+        }
+        ASTNode::If { then_branch, else_branch, .. } => {
+            forget_written(then_branch, env);
+            if let Some(e) = else_branch {
+                forget_written(e, env);
+            }
+        }
+        ASTNode::While { body, step, .. } => {
+            forget_written(body, env);
+            if let Some(step) = step {
+                forget_written(step, env);
+            }
+        }
+        ASTNode::Return(_) | ASTNode::ExprStmt(_) | ASTNode::Print(_) | ASTNode::Printf { .. } | ASTNode::Break
+        | ASTNode::Continue
+        | ASTNode::FunctionDef { .. } => {}
+    }
+}
+
+//folds an expression, substituting variables with known constant values
+fn fold_expr(expr: &Expr, env: &ConstEnv) -> Expr {
+    let (lhs, rhs) = match expr {
+        Expr::Number(n) => return Expr::Number(*n),
+        Expr::Variable(name) | Expr::Var(name) => {
+            return match env.values.get(name) {
+                Some(&n) => Expr::Number(n),
+                None => expr.clone(),
+            };
+        }
+        Expr::Call(name, args) => {
+            return Expr::Call(name.clone(), args.iter().map(|a| fold_expr(a, env)).collect());
+        }
+        Expr::Neg(e) => {
+            return match fold_expr(e, env) {
+                Expr::Number(n) => Expr::Number(n.wrapping_neg()),
+                inner => Expr::Neg(Box::new(inner)),
+            };
+        }
+        Expr::Not(e) => {
+            return match fold_expr(e, env) {
+                Expr::Number(n) => Expr::Number((n == 0) as i64),
+                inner => Expr::Not(Box::new(inner)),
+            };
+        }
+        Expr::BitNot(e) => {
+            return match fold_expr(e, env) {
+                Expr::Number(n) => Expr::Number(!n),
+                inner => Expr::BitNot(Box::new(inner)),
+            };
+        }
+        Expr::Add(l, r) | Expr::Sub(l, r) | Expr::Mul(l, r) | Expr::Div(l, r)
+        | Expr::Mod(l, r) | Expr::Equal(l, r) | Expr::Less(l, r) | Expr::Greater(l, r)
+        | Expr::NotEqual(l, r) | Expr::LessEqual(l, r) | Expr::GreaterEqual(l, r)
+        | Expr::BitAnd(l, r) | Expr::BitOr(l, r) | Expr::BitXor(l, r) | Expr::Shl(l, r)
+        | Expr::Shr(l, r) | Expr::And(l, r) | Expr::Or(l, r) => {
+            (fold_expr(l, env), fold_expr(r, env))
+        }
+    };
+
+    if let (Expr::Number(a), Expr::Number(b)) = (&lhs, &rhs) {
+        let (a, b) = (*a, *b);
+        let folded = match expr {
+            Expr::Add(..) => Some(a.wrapping_add(b)),
+            Expr::Sub(..) => Some(a.wrapping_sub(b)),
+            Expr::Mul(..) => Some(a.wrapping_mul(b)),
+            //leave division by zero for the VM to report at runtime
+            Expr::Div(..) => a.checked_div(b),
+            Expr::Mod(..) => a.checked_rem(b),
+            Expr::Equal(..) => Some((a == b) as i64),
+            Expr::Less(..) => Some((a < b) as i64),
+            Expr::Greater(..) => Some((a > b) as i64),
+            Expr::NotEqual(..) => Some((a != b) as i64),
+            Expr::LessEqual(..) => Some((a <= b) as i64),
+            Expr::GreaterEqual(..) => Some((a >= b) as i64),
+            Expr::BitAnd(..) => Some(a & b),
+            Expr::BitOr(..) => Some(a | b),
+            Expr::BitXor(..) => Some(a ^ b),
+            Expr::Shl(..) => Some(a.wrapping_shl(b as u32)),
+            Expr::Shr(..) => Some(a.wrapping_shr(b as u32)),
+            Expr::And(..) => Some((a != 0 && b != 0) as i64),
+            Expr::Or(..) => Some((a != 0 || b != 0) as i64),
+            _ => None,
+        };
+        if let Some(n) = folded {
+            return Expr::Number(n);
+        }
+    }
+
+    let (l, r) = (Box::new(lhs), Box::new(rhs));
+    match expr {
+        Expr::Add(..) => Expr::Add(l, r),
+        Expr::Sub(..) => Expr::Sub(l, r),
+        Expr::Mul(..) => Expr::Mul(l, r),
+        Expr::Div(..) => Expr::Div(l, r),
+        Expr::Mod(..) => Expr::Mod(l, r),
+        Expr::Equal(..) => Expr::Equal(l, r),
+        Expr::Less(..) => Expr::Less(l, r),
+        Expr::Greater(..) => Expr::Greater(l, r),
+        Expr::NotEqual(..) => Expr::NotEqual(l, r),
+        Expr::LessEqual(..) => Expr::LessEqual(l, r),
+        Expr::GreaterEqual(..) => Expr::GreaterEqual(l, r),
+        Expr::BitAnd(..) => Expr::BitAnd(l, r),
+        Expr::BitOr(..) => Expr::BitOr(l, r),
+        Expr::BitXor(..) => Expr::BitXor(l, r),
+        Expr::Shl(..) => Expr::Shl(l, r),
+        Expr::Shr(..) => Expr::Shr(l, r),
+        Expr::And(..) => Expr::And(l, r),
+        Expr::Or(..) => Expr::Or(l, r),
+        _ => unreachable!("leaf expressions return early"),
+    }
+}
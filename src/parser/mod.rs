@@ -1,40 +1,131 @@
-use crate::codegen::ASTNode;
+use crate::codegen::{ASTNode, Expr, Type};
 use crate::lexer::Token;
-use crate::Expr;
+use std::fmt;
 use std::iter::Peekable;
 use std::slice::Iter;
 
-///parses a sequence of tokens into an AST
+///default for the deepest nesting the parser and the passes that recurse over the AST will accept
+///counted as open '(' / '{' plus the operators seen so far in the current statement
+pub const DEFAULT_MAX_NESTING: usize = 4000;
+
+///errors detected before recursive parsing starts
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    TooDeep { limit: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::TooDeep { limit } => {
+                write!(f, "expression nested too deeply (limit is {} levels)", limit)
+            }
+        }
+    }
+}
+
+///rejects inputs whose nesting would overflow the call stack of a recursive pass
+///'~~~1' recurses in parse_unary and '1+1+1' builds a chain as deep as it is long,
+///so every operator in a statement counts as a level, an upper bound on the tree's depth
+pub fn check_nesting(tokens: &[Token], limit: usize) -> Result<(), ParseError> {
+    let mut brackets: usize = 0;
+    let mut operators: usize = 0;
+    for tok in tokens {
+        match tok {
+            Token::LParen | Token::LBrace => brackets += 1,
+            Token::RParen => brackets = brackets.saturating_sub(1),
+            Token::RBrace => {
+                brackets = brackets.saturating_sub(1);
+                operators = 0;
+            }
+            Token::Semicolon => operators = 0,
+            Token::Plus | Token::Minus | Token::Star | Token::Div | Token::Divide | Token::Mod
+            | Token::Equal | Token::NotEqual | Token::Less | Token::LessEqual | Token::Greater
+            | Token::GreaterEqual | Token::Not | Token::And | Token::Or | Token::BitAnd
+            | Token::BitOr | Token::Tilde | Token::Caret | Token::Shl | Token::Shr
+            | Token::PlusEq | Token::MinusEq | Token::StarEq | Token::DivEq | Token::ModEq => {
+                operators += 1
+            }
+            _ => continue,
+        }
+        if brackets + operators > limit {
+            return Err(ParseError::TooDeep { limit });
+        }
+    }
+    Ok(())
+}
+
+///parses a sequence of tokens into an AST, panicking on any error
 pub fn parse(tokens: &[Token]) -> ASTNode {
+    try_parse(tokens, DEFAULT_MAX_NESTING).unwrap_or_else(|e| panic!("{}", e))
+}
+
+///parses like parse, but hands nesting deeper than max_nesting back as a ParseError
+pub fn try_parse(tokens: &[Token], max_nesting: usize) -> Result<ASTNode, ParseError> {
+    check_nesting(tokens, max_nesting)?;
     let mut iter = tokens.iter().peekable();
     //eprintln!("DEBUG_TOKENS = {:#?}", tokens);
 
-    //skip everything until we see exactly 'int main() {'
+    //a program is a sequence of function definitions, main is just one of them
+    let mut functions = Vec::new();
+    while iter.peek().is_some() {
+        functions.push(parse_function(&mut iter));
+    }
+
+    Ok(ASTNode::Sequence(functions))
+}
+
+///parses 'int name(...) { ... }' into a FunctionDef
+fn parse_function(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
+    match iter.next() {
+        Some(Token::Int) => {}
+        other => panic!("Expected function definition, got {:?}", other),
+    }
+    let name = match iter.next() {
+        Some(Token::Identifier(name)) => name.clone(),
+        other => panic!("Expected function name, got {:?}", other),
+    };
+
+    expect_token(iter, Token::LParen);
+    let params = parse_params(iter, &name);
+    expect_token(iter, Token::LBrace);
+    let body = parse_body(iter);
+
+    ASTNode::FunctionDef { name, params, body: Box::new(body) }
+}
+
+///parses 'int a, int b)' after a function's '(' and returns the parameter names
+fn parse_params(iter: &mut Peekable<Iter<Token>>, func: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    if let Some(Token::RParen) = iter.peek() {
+        iter.next(); //'()' takes no parameters
+        return params;
+    }
     loop {
+        expect_token(iter, Token::Int);
         match iter.next() {
-            Some(Token::Identifier(name)) if name == "main" => {
-                //consume tokens until the "{"
-                while let Some(tok) = iter.next() {
-                    if *tok == Token::LBrace {
-                        break;
-                    }
-                }
-                break;
-            }
-            Some(_) => {
-                // not yet "main", keep skipping
-            }
-            None => panic!("couldn’t find 'main' in tokens"),
+            Some(Token::Identifier(param)) => params.push(param.clone()),
+            other => panic!("Expected parameter name in {}, got {:?}", func, other),
+        }
+        match iter.next() {
+            Some(Token::Comma) => {}
+            Some(Token::RParen) => return params,
+            other => panic!("Expected ',' or ')' after parameter of {}, got {:?}", func, other),
         }
     }
+}
+
+///parses the statements of a function body up to and including its closing '}'
+fn parse_body(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
     let mut statements = Vec::new();
     while let Some(tok) = iter.peek() {
         match tok {
-            Token::Return | Token::If | Token::While
-          | Token::LBrace  | Token::Int | Token::Identifier(_) =>
-                statements.push(parse_stmt(&mut iter)),
+            Token::Return | Token::If | Token::While | Token::For | Token::Break | Token::Continue
+          | Token::LBrace  | Token::Int | Token::Char | Token::Identifier(_)
+          | Token::PlusPlus | Token::MinusMinus =>
+                statements.push(parse_stmt(iter)),
             Token::RBrace => { iter.next(); break; }
-            other => panic!("Unexpected token in main body: {:?}", other),
+            other => panic!("Unexpected token in function body: {:?}", other),
         }
     }
 
@@ -42,32 +133,88 @@ pub fn parse(tokens: &[Token]) -> ASTNode {
 }
 
 
-///parses a variable declaration from the token stream
-fn parse_declaration(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
-    let name = match iter.next() { //consume 'int'
-        Some(Token::Identifier(name)) => name.clone(),
-        _ => panic!("Expected variable name"),
-    };
-
-    expect_token(iter, Token::Assign); //consume '='
-    let expr = parse_expr(iter); //parse the expression
+///parses a variable declaration from the token stream, after its type keyword
+///'int a = 1, b;' declares each name in turn and comes back as a Sequence
+///a '*' before a name makes just that variable a pointer, as in C
+fn parse_declaration(iter: &mut Peekable<Iter<Token>>, base: Type) -> ASTNode {
+    let mut decls = Vec::new();
+    loop {
+        let ty = if let Some(Token::Star) = iter.peek() {
+            iter.next(); //consume '*'
+            Type::Ptr
+        } else {
+            base
+        };
+        let name = match iter.next() { //consume 'int'
+            Some(Token::Identifier(name)) => name.clone(),
+            _ => panic!("Expected variable name"),
+        };
+
+        //'int x;' still reserves the slot, starting it at 0
+        let expr = if let Some(Token::Semicolon | Token::Comma) = iter.peek() {
+            Box::new(Expr::Number(0))
+        } else {
+            expect_token(iter, Token::Assign); //consume '='
+            parse_expr(iter) //parse the expression
+        };
+        decls.push(ASTNode::Declaration(name, expr, ty));
+
+        if let Some(Token::Comma) = iter.peek() {
+            iter.next(); //consume ','
+        } else {
+            break;
+        }
+    }
     expect_token(iter, Token::Semicolon); //consume ';'
 
-    ASTNode::Declaration(name, expr) //return the declaration
+    if decls.len() == 1 {
+        decls.pop().unwrap() //return the declaration
+    } else {
+        ASTNode::Sequence(decls)
+    }
 }
 
 ///parses an assignment statement from the token stream
 fn parse_assignment(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
-    let name = match iter.next() { //consume 'int'
+    let assignment = parse_assignment_expr(iter);
+    expect_token(iter, Token::Semicolon);
+    assignment
+}
+
+///parses 'name = expr' without the trailing ';', as used by a for-loop step
+///'x op= e' is desugared to 'x = x op e', reloading x is fine as locals have no side effects
+///'x++' and '++x' (and '--') only appear as statements, so both become 'x = x + 1'
+fn parse_assignment_expr(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
+    let is_incr = |t: &&Token| matches!(t, Token::PlusPlus | Token::MinusMinus);
+    let prefix = iter.next_if(is_incr);
+    let name = match iter.next() { //consume the variable being assigned
         Some(Token::Identifier(name)) => name.clone(),
         _ => panic!("Expected variable name"),
     };
+    if let Some(tok) = prefix.or_else(|| iter.next_if(is_incr)) {
+        let current = Box::new(Expr::Var(name.clone()));
+        let one = Box::new(Expr::Number(1));
+        let value = if *tok == Token::PlusPlus { Expr::Add(current, one) } else { Expr::Sub(current, one) };
+        return ASTNode::Assignment(name, Box::new(value));
+    }
 
-    expect_token(iter, Token::Assign);
+    let op = match iter.next() {
+        Some(tok @ (Token::Assign | Token::PlusEq | Token::MinusEq | Token::StarEq | Token::DivEq
+        | Token::ModEq)) => tok,
+        other => panic!("Expected {:?}, got {:?}", Token::Assign, other),
+    };
     let expr = parse_expr(iter); //parse the expression
-    expect_token(iter, Token::Semicolon);
+    let current = Box::new(Expr::Var(name.clone()));
+    let value = match op {
+        Token::PlusEq => Box::new(Expr::Add(current, expr)),
+        Token::MinusEq => Box::new(Expr::Sub(current, expr)),
+        Token::StarEq => Box::new(Expr::Mul(current, expr)),
+        Token::DivEq => Box::new(Expr::Div(current, expr)),
+        Token::ModEq => Box::new(Expr::Mod(current, expr)),
+        _ => expr,
+    };
 
-    ASTNode::Assignment(name, expr)
+    ASTNode::Assignment(name, value)
 }
 
 ///parses an individual statement from the token stream
@@ -85,9 +232,18 @@ fn parse_stmt(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
             } else { //consume the token
                 panic!("Expected string literal in printf");
             };
+            //then any number of ', expr' arguments for the %d placeholders
+            let mut args = Vec::new();
+            while let Some(Token::Comma) = iter.peek() {
+                iter.next();
+                args.push(*parse_expr(iter));
+            }
             expect_token(iter, Token::RParen);
             expect_token(iter, Token::Semicolon);
-            return ASTNode::Print(s);
+            if args.is_empty() {
+                return ASTNode::Print(s);
+            }
+            return ASTNode::Printf { fmt: s, args };
         }
     }
     match iter.peek() {
@@ -108,12 +264,43 @@ fn parse_stmt(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
             iter.next(); //consume 'while'
             parse_while(iter)
         }
+        Some(Token::For) => {
+            iter.next(); //consume 'for'
+            parse_for(iter)
+        }
+        //codegen rejects these when they are not inside a loop
+        Some(Token::Break) => {
+            iter.next(); //consume 'break'
+            expect_token(iter, Token::Semicolon);
+            ASTNode::Break
+        }
+        Some(Token::Continue) => {
+            iter.next(); //consume 'continue'
+            expect_token(iter, Token::Semicolon);
+            ASTNode::Continue
+        }
         Some(Token::Int) => {
             iter.next(); //consume 'int'
-            parse_declaration(iter)
+            parse_declaration(iter, Type::Int)
+        }
+        Some(Token::Char) => {
+            iter.next(); //consume 'char'
+            parse_declaration(iter, Type::Char)
+        }
+        Some(Token::PlusPlus | Token::MinusMinus) => {
+            parse_assignment(iter) //'++i;'
         }
         Some(Token::Identifier(_)) => {
-            parse_assignment(iter)
+            //'name(' starts a call made for its side effects, anything else is an assignment
+            let mut ahead = iter.clone();
+            ahead.next();
+            if let Some(Token::LParen) = ahead.peek() {
+                let expr = parse_expr(iter);
+                expect_token(iter, Token::Semicolon);
+                ASTNode::ExprStmt(expr)
+            } else {
+                parse_assignment(iter)
+            }
         }
 
 
@@ -132,7 +319,41 @@ fn parse_while(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
     ASTNode::While {
         condition,
         body: Box::new(body),
+        step: None,
+    }
+}
+
+///parses 'for (init; cond; step) body' and desugars it into
+///'{ init; while (cond) { body; step; } }', the outer braces scoping init to the loop
+///step is kept apart from body so that 'continue' still runs it
+///any of the three clauses may be left empty, a missing condition means 'forever'
+fn parse_for(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
+    expect_token(iter, Token::LParen);
+
+    let mut stmts = Vec::new();
+    if let Some(Token::Semicolon) = iter.peek() {
+        iter.next();
+    } else {
+        stmts.push(parse_stmt(iter)); //declaration or assignment, eats its ';'
     }
+
+    let condition = match iter.peek() {
+        Some(Token::Semicolon) => Box::new(Expr::Number(1)),
+        _ => parse_expr(iter),
+    };
+    expect_token(iter, Token::Semicolon);
+
+    let step = match iter.peek() {
+        Some(Token::RParen) => None,
+        _ => Some(parse_assignment_expr(iter)),
+    };
+    expect_token(iter, Token::RParen);
+
+    let body = parse_stmt(iter);
+    stmts.push(ASTNode::While { condition, body: Box::new(body), step: step.map(Box::new) });
+
+    //a variable declared in the init clause only lives as long as the loop
+    ASTNode::Block(stmts)
 }
 
 ///parses a block of statements enclosed in braces
@@ -146,8 +367,10 @@ fn parse_block(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
                 iter.next();
                 break;
             }
-            //also allow variable declarations ('int ...') inside blocks
-            Token::Return | Token::If | Token::While | Token::LBrace | Token::Int => {
+            //also allow variable declarations ('int ...') and assignments inside blocks
+            Token::Return | Token::If | Token::While | Token::For | Token::Break | Token::Continue
+            | Token::LBrace | Token::Int | Token::Char | Token::Identifier(_) | Token::PlusPlus
+            | Token::MinusMinus => {
                  stmts.push(parse_stmt(iter));
              }
             t => {
@@ -158,7 +381,7 @@ fn parse_block(iter: &mut Peekable<Iter<Token>>) -> ASTNode {
     }
 
 
-    ASTNode::Sequence(stmts)
+    ASTNode::Block(stmts)
 }
 
 
@@ -200,8 +423,8 @@ fn expect_token(iter: &mut Peekable<Iter<Token>>, expected: Token) {
 
 ///parses a primary expression from the token stream
 fn parse_primary(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
-    match iter.next() {
-        Some(Token::Number(n)) => Box::new(Expr::Number(*n)),
+    let expr = match iter.next() {
+        Some(Token::Number(n)) | Some(Token::CharLiteral(n)) => Box::new(Expr::Number(*n)),
 
         Some(Token::Identifier(name)) => {
             let name = name.clone();
@@ -241,27 +464,52 @@ fn parse_primary(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
         }
 
         other => panic!("Expected number, variable, or '(', got {:?}", other),
+    };
+
+    //'->' and '.' are lexed ahead of struct support but cannot be used yet
+    if let Some(Token::Arrow | Token::Dot) = iter.peek() {
+        panic!("struct member access not yet supported");
+    }
+    expr
+}
+
+///parses prefix operators, which bind tighter than any binary operator
+fn parse_unary(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    match iter.peek() {
+        Some(Token::Minus) => {
+            iter.next(); //consume '-'
+            Box::new(Expr::Neg(parse_unary(iter)))
+        }
+        Some(Token::Not) => {
+            iter.next(); //consume '!'
+            Box::new(Expr::Not(parse_unary(iter)))
+        }
+        Some(Token::Tilde) => {
+            iter.next(); //consume '~'
+            Box::new(Expr::BitNot(parse_unary(iter)))
+        }
+        _ => parse_primary(iter),
     }
 }
 
 ///now handle '*' '/' '%' all at the same (high) precedence
 fn parse_term(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
-    let mut node = parse_primary(iter);
+    let mut node = parse_unary(iter);
     loop {
         match iter.peek() {
             Some(Token::Star) => {
                 iter.next();
-                let rhs = parse_primary(iter);
+                let rhs = parse_unary(iter);
                 node = Box::new(Expr::Mul(node, rhs));
             }
             Some(Token::Div) => {
                 iter.next();
-                let rhs = parse_primary(iter);
+                let rhs = parse_unary(iter);
                 node = Box::new(Expr::Div(node, rhs));
             }
             Some(Token::Mod) => {
                 iter.next();
-                let rhs = parse_primary(iter);
+                let rhs = parse_unary(iter);
                 node = Box::new(Expr::Mod(node, rhs));
             }
             _ => break,
@@ -291,6 +539,131 @@ fn parse_add(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
     node
 }
 
+///shifts '<<' '>>' sit between the additive and relational operators
+fn parse_shift(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_add(iter);
+    loop {
+        match iter.peek() {
+            Some(Token::Shl) => {
+                iter.next();
+                let rhs = parse_add(iter);
+                node = Box::new(Expr::Shl(node, rhs));
+            }
+            Some(Token::Shr) => {
+                iter.next();
+                let rhs = parse_add(iter);
+                node = Box::new(Expr::Shr(node, rhs));
+            }
+            _ => break,
+        }
+    }
+    node
+}
+
+///relational operators '<' '>' '<=' '>=' bind looser than the shifts
+fn parse_comparison(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_shift(iter);
+    loop {
+        match iter.peek() {
+            Some(Token::Less) => {
+                iter.next();
+                let rhs = parse_shift(iter);
+                node = Box::new(Expr::Less(node, rhs));
+            }
+            Some(Token::Greater) => {
+                iter.next();
+                let rhs = parse_shift(iter);
+                node = Box::new(Expr::Greater(node, rhs));
+            }
+            Some(Token::LessEqual) => {
+                iter.next();
+                let rhs = parse_shift(iter);
+                node = Box::new(Expr::LessEqual(node, rhs));
+            }
+            Some(Token::GreaterEqual) => {
+                iter.next();
+                let rhs = parse_shift(iter);
+                node = Box::new(Expr::GreaterEqual(node, rhs));
+            }
+            _ => break,
+        }
+    }
+    node
+}
+
+///equality operators '==' '!=' bind looser than the relational ones, as in C
+fn parse_equality(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_comparison(iter);
+    loop {
+        match iter.peek() {
+            Some(Token::Equal) => {
+                iter.next();
+                let rhs = parse_comparison(iter);
+                node = Box::new(Expr::Equal(node, rhs));
+            }
+            Some(Token::NotEqual) => {
+                iter.next();
+                let rhs = parse_comparison(iter);
+                node = Box::new(Expr::NotEqual(node, rhs));
+            }
+            _ => break,
+        }
+    }
+    node
+}
+
+///the bitwise operators come next, '&' tightest, then '^', then '|', as in C
+fn parse_bit_and(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_equality(iter);
+    while let Some(Token::BitAnd) = iter.peek() {
+        iter.next();
+        let rhs = parse_equality(iter);
+        node = Box::new(Expr::BitAnd(node, rhs));
+    }
+    node
+}
+
+fn parse_bit_xor(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_bit_and(iter);
+    while let Some(Token::Caret) = iter.peek() {
+        iter.next();
+        let rhs = parse_bit_and(iter);
+        node = Box::new(Expr::BitXor(node, rhs));
+    }
+    node
+}
+
+fn parse_bit_or(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_bit_xor(iter);
+    while let Some(Token::BitOr) = iter.peek() {
+        iter.next();
+        let rhs = parse_bit_xor(iter);
+        node = Box::new(Expr::BitOr(node, rhs));
+    }
+    node
+}
+
+///'&&' binds tighter than '||', and both bind looser than the bitwise operators
+fn parse_logical_and(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_bit_or(iter);
+    while let Some(Token::And) = iter.peek() {
+        iter.next();
+        let rhs = parse_bit_or(iter);
+        node = Box::new(Expr::And(node, rhs));
+    }
+    node
+}
+
+fn parse_logical(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
+    let mut node = parse_logical_and(iter);
+    while let Some(Token::Or) = iter.peek() {
+        iter.next();
+        let rhs = parse_logical_and(iter);
+        node = Box::new(Expr::Or(node, rhs));
+    }
+    node
+}
+
 fn parse_expr(iter: &mut Peekable<Iter<Token>>) -> Box<Expr> {
-    parse_add(iter)
+    parse_logical(iter)
 }
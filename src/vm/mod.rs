@@ -1,23 +1,94 @@
 #![allow(dead_code)] //suppress warnings for unused opcodes
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///index of an instruction in the program, used as a jump/call target
+///
+///```
+///use c4_rust_ghiyathi::vm::{CodeAddr, Instruction};
+///let _ = Instruction::JMP(CodeAddr(3));
+///```
+///
+///a data address is a different type, so it cannot be used as a jump target:
+///
+///```compile_fail
+///use c4_rust_ghiyathi::vm::{DataAddr, Instruction};
+///let _ = Instruction::JMP(DataAddr(3));
+///```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodeAddr(pub usize);
+
+///index of a memory cell, used by the load/store instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataAddr(pub usize);
+
+///data addresses are split into three segments:
+///  [0, HEAP_BASE)           stack, word cells
+///  [HEAP_BASE, BYTES_BASE)  heap, word cells handed out by MALC
+///  [BYTES_BASE, ..)         byte segment, one u8 per address, handed out by MALC with MALC_BYTES
+///LI/SI only work on word cells, LC/SC on any segment (the low byte of a word cell)
+pub const HEAP_BASE: usize = 1 << 20;
+
+///first address of the byte segment
+pub const BYTES_BASE: usize = 1 << 30;
+
+///MALC flag asking for a region of the byte segment instead of heap words
+pub const MALC_BYTES: i64 = 1;
+
+///most cells the heap can grow to before its addresses would run into the byte segment
+const MAX_HEAP_CELLS: usize = BYTES_BASE - HEAP_BASE;
+
+///most bytes the byte segment can grow to
+const MAX_BYTES: usize = 1 << 30;
+
+///first fd OPEN hands out when syscalls are enabled, 0-2 belong to stdio
+pub const FIRST_FD: i64 = 3;
+
+///default limit on the number of stack cells, also the most the stack can ever hold
+///since stack index HEAP_BASE would be the first heap address
+pub const DEFAULT_MAX_STACK: usize = HEAP_BASE;
+
+///value fresh locals are filled with when frame scrubbing is on
+///LEV and ADJ truncate the stack, so a frame's slots stop existing when it is left
+///and the next frame's slots are new cells made by ENT: filling them there gives the same
+///result as scrubbing on exit, no stale value from an earlier call can ever be read
+pub const SCRUB_SENTINEL: i64 = 0xDEAD;
+
 ///this module will implement a simple stack-based virtual machine for executing instructions
 #[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)] //opcode names mirror the original C4
 pub enum Instruction {
     IMM(i64),
     PSH,
+    POP, // discards the top, e.g. the unused result of an expression statement
     ADD,
     SUB,
     MUL,
     DIV,
     MOD,
-    JMP(usize),
-    BZ(usize),
-    BNZ(usize),
-    JSR(usize),
+    JMP(CodeAddr),
+    BZ(CodeAddr),
+    BNZ(CodeAddr),
+    JSR(CodeAddr),
     ENT(usize),
+    ///a call, as in C4, goes
+    ///  caller pushes args:   .. arg0 arg1
+    ///  JSR pushes the pc:    .. arg0 arg1 ret
+    ///  ENT(k) saves bp:      .. arg0 arg1 ret old_bp | k locals   (bp points just past old_bp)
+    ///  LEV leaves the value: .. arg0 arg1 result
+    ///  ADJ(2) drops the args under the result: .. result
     ADJ(usize),
     LEV,
-    LEA(usize),
+    LEA(isize), // offsets are relative to bp, negative ones reach the caller's arguments
+    LOAD(isize), // fused LEA + LI for reading a local
+    STORE(isize), // fused LEA + SI for writing a local, pops the value
     LI,
     LC,
     SI,
@@ -33,7 +104,294 @@ pub enum Instruction {
     EQ, // for ==
     LT, // for <
     GT, // for >
+    NE, // for !=
+    LE, // for <=
+    GE, // for >=
+    NEG, // for unary -
+    BNOT, // for unary ~
+    AND, // for &
+    OR, // for |
+    XOR, // for ^
+    SHL, // for <<
+    SHR, // for >>, arithmetic so the sign is kept
+    TIME, // for the time() builtin, pushes the VM clock
+    NOP, // does nothing, used for padding and as a spot to patch breakpoints into
     PrintfStr(String), // for printf string
+    PrintfFmt(String, usize), // printf with that many %d arguments popped from the stack
+}
+
+impl Instruction {
+    ///net change in stack height caused by executing this instruction
+    ///control transfer (JSR/LEV) is accounted for by the caller of this
+    pub fn stack_effect(&self) -> isize {
+        match self {
+            Instruction::IMM(_) | Instruction::PSH | Instruction::LEA(_) | Instruction::LOAD(_)
+            | Instruction::TIME => 1,
+            Instruction::ADD | Instruction::SUB | Instruction::MUL | Instruction::DIV
+            | Instruction::MOD | Instruction::EQ | Instruction::LT | Instruction::GT
+            | Instruction::NE | Instruction::LE | Instruction::GE | Instruction::AND
+            | Instruction::OR | Instruction::XOR | Instruction::SHL | Instruction::SHR => -1,
+            Instruction::BZ(_) | Instruction::BNZ(_) | Instruction::STORE(_) | Instruction::POP => -1,
+            Instruction::JSR(_) => 1, //return address
+            Instruction::ENT(size) => 1 + *size as isize, //saved bp plus locals
+            Instruction::ADJ(n) => -(*n as isize),
+            Instruction::SI | Instruction::SC => -2,
+            Instruction::FREE => -1,
+            Instruction::PrintfFmt(_, n) => -(*n as isize),
+            Instruction::MSET => -3,
+            Instruction::MCMP | Instruction::READ => -2,
+            Instruction::OPEN => -1,
+            Instruction::JMP(_) | Instruction::LEV | Instruction::LI | Instruction::LC
+            | Instruction::NEG | Instruction::BNOT
+            | Instruction::EXIT | Instruction::MALC | Instruction::CLOS
+            | Instruction::PrintfStr(_) | Instruction::NOP => 0,
+        }
+    }
+
+    ///short human description of what the instruction does to the stack
+    pub fn explain(&self) -> String {
+        match self {
+            Instruction::IMM(n) => format!("push {}", n),
+            Instruction::PSH => "push a copy of the top".to_string(),
+            Instruction::POP => "pop a".to_string(),
+            Instruction::ADD => "pop b, pop a, push a+b".to_string(),
+            Instruction::SUB => "pop b, pop a, push a-b".to_string(),
+            Instruction::MUL => "pop b, pop a, push a*b".to_string(),
+            Instruction::DIV => "pop b, pop a, push a/b".to_string(),
+            Instruction::MOD => "pop b, pop a, push a%b".to_string(),
+            Instruction::EQ => "pop b, pop a, push a==b".to_string(),
+            Instruction::LT => "pop b, pop a, push a<b".to_string(),
+            Instruction::GT => "pop b, pop a, push a>b".to_string(),
+            Instruction::NE => "pop b, pop a, push a!=b".to_string(),
+            Instruction::LE => "pop b, pop a, push a<=b".to_string(),
+            Instruction::GE => "pop b, pop a, push a>=b".to_string(),
+            Instruction::NEG => "pop a, push -a".to_string(),
+            Instruction::BNOT => "pop a, push ~a".to_string(),
+            Instruction::AND => "pop b, pop a, push a&b".to_string(),
+            Instruction::OR => "pop b, pop a, push a|b".to_string(),
+            Instruction::XOR => "pop b, pop a, push a^b".to_string(),
+            Instruction::SHL => "pop b, pop a, push a<<b".to_string(),
+            Instruction::SHR => "pop b, pop a, push a>>b".to_string(),
+            Instruction::TIME => "push the current time".to_string(),
+            Instruction::NOP => "nothing".to_string(),
+            Instruction::JMP(t) => format!("jump to {}", t.0),
+            Instruction::BZ(t) => format!("pop a, jump to {} if a == 0", t.0),
+            Instruction::BNZ(t) => format!("pop a, jump to {} if a != 0", t.0),
+            Instruction::JSR(t) => format!("push return address, jump to {}", t.0),
+            Instruction::ENT(n) => format!("push bp, set bp to top, reserve {} locals", n),
+            Instruction::ADJ(n) => format!("drop {} values below the top", n),
+            Instruction::LEV => "pop result, drop frame, restore bp, return and push result".to_string(),
+            Instruction::LEA(o) => format!("push address of local {}", o),
+            Instruction::LOAD(o) => format!("push local {}", o),
+            Instruction::STORE(o) => format!("pop a, store a into local {}", o),
+            Instruction::LI => "pop addr, push word at addr".to_string(),
+            Instruction::LC => "pop addr, push byte at addr".to_string(),
+            Instruction::SI => "pop a, pop addr, store word a at addr".to_string(),
+            Instruction::SC => "pop a, pop addr, store byte a at addr".to_string(),
+            Instruction::EXIT => "stop, exit value is the top".to_string(),
+            Instruction::MALC => "pop flags, pop size, push status, push pointer".to_string(),
+            Instruction::FREE => "pop pointer".to_string(),
+            Instruction::MSET => "pop 3 arguments".to_string(),
+            Instruction::MCMP => "pop 3 arguments, push result".to_string(),
+            Instruction::OPEN => "pop 2 arguments, push fd".to_string(),
+            Instruction::READ => "pop 3 arguments, push count".to_string(),
+            Instruction::CLOS => "pop fd, push status".to_string(),
+            Instruction::PrintfStr(_) => "print the string".to_string(),
+            Instruction::PrintfFmt(_, n) => format!("pop {} values, print them with the format", n),
+        }
+    }
+}
+
+///formats the program one instruction per line, optionally with its stack effect
+pub fn disassemble(program: &[Instruction], explain: bool) -> String {
+    let mut out = String::new();
+    for (pc, instr) in program.iter().enumerate() {
+        let text = format!("{:?}", instr);
+        if explain {
+            out.push_str(&format!("{:>4}  {:<20} ; {}\n", pc, text, instr.explain()));
+        } else {
+            out.push_str(&format!("{:>4}  {}\n", pc, text));
+        }
+    }
+    out
+}
+
+///stable 64-bit FNV-1a hash of the program, for checking two builds are identical
+///hashes each instruction's Debug text so it does not depend on memory layout
+pub fn checksum(program: &[Instruction]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for instr in program {
+        for byte in format!("{:?}\n", instr).bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+///statically estimates the deepest the stack can get while running the program
+///walks every reachable path from pc 0, following both sides of each branch
+///a call is assumed to return with its result in place of the return address
+///returns None when some loop keeps growing the stack, since no bound exists
+pub fn estimate_max_stack(program: &[Instruction]) -> Option<usize> {
+    let mut depth_at: Vec<Option<isize>> = vec![None; program.len()];
+    let mut work = vec![(0usize, 0isize)];
+    let mut max = 0isize;
+
+    while let Some((pc, depth)) = work.pop() {
+        let Some(instr) = program.get(pc) else { continue };
+        match depth_at[pc] {
+            Some(seen) if seen >= depth => continue,
+            Some(_) => return None, //reached again with a taller stack
+            None => depth_at[pc] = Some(depth),
+        }
+
+        let after = (depth + instr.stack_effect()).max(0);
+        max = max.max(depth).max(after);
+
+        match instr {
+            Instruction::JMP(target) => work.push((target.0, after)),
+            Instruction::BZ(target) | Instruction::BNZ(target) => {
+                work.push((target.0, after));
+                work.push((pc + 1, after));
+            }
+            Instruction::JSR(target) => {
+                work.push((target.0, after));
+                work.push((pc + 1, after));
+            }
+            Instruction::LEV | Instruction::EXIT => {}
+            _ => work.push((pc + 1, after)),
+        }
+    }
+
+    Some(max as usize)
+}
+
+///when program output written by printf is flushed to the underlying writer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputBuffering {
+    Line, //flush whenever a newline is written
+    Full, //flush only when the program exits
+    None, //flush after every write
+}
+
+impl FromStr for OutputBuffering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(OutputBuffering::Line),
+            "full" => Ok(OutputBuffering::Full),
+            "none" => Ok(OutputBuffering::None),
+            other => Err(format!("unknown buffering mode '{}' (expected line, full or none)", other)),
+        }
+    }
+}
+
+///where the time() builtin gets its value from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    Fixed(i64), //always the same value, so runs are reproducible
+    Real,       //seconds since the unix epoch
+}
+
+impl Clock {
+    fn now(&self) -> i64 {
+        match self {
+            Clock::Fixed(t) => *t,
+            Clock::Real => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0),
+        }
+    }
+}
+
+///errors that stop the VM before the program exits normally
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    StackUnderflow,     //popped from an empty stack, or left a frame that was never entered
+    StackOverflow,      //grew past the VM's max_stack, e.g. runaway recursion
+    PcOutOfBounds,      //ran past the end of the program
+    DivByZero,          //DIV or MOD with a zero divisor
+    BadAddress(usize),  //load or store outside the stack
+    BudgetExceeded,     //run_with_budget ran out of steps before EXIT
+    Output(io::ErrorKind), //printf output could not be written or flushed
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::PcOutOfBounds => write!(f, "program counter out of bounds"),
+            VmError::DivByZero => write!(f, "division by zero"),
+            VmError::BadAddress(addr) => write!(f, "bad address {}", addr),
+            VmError::BudgetExceeded => write!(f, "instruction budget exceeded"),
+            VmError::Output(kind) => write!(f, "failed to write program output: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+///a VM error from run_capturing, along with what the program printed before it stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedError {
+    pub error: VmError,
+    pub output: String,
+}
+
+impl fmt::Display for CapturedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for CapturedError {}
+
+///expands a printf format string
+///"%%" prints a single '%', and a '%' at the very end is printed as-is
+///"%d" takes the next value from args, or is printed literally when none is left
+pub fn format_printf(fmt: &str, args: &[i64]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('%') => {
+                chars.next();
+                out.push('%');
+            }
+            Some('d') => {
+                chars.next();
+                match args.next() {
+                    Some(v) => out.push_str(&v.to_string()),
+                    None => out.push_str("%d"),
+                }
+            }
+            _ => out.push('%'), //lone '%' or unsupported specifier, keep it
+        }
+    }
+    out
+}
+
+///writer that appends into a shared buffer, used to capture program output
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 ///simple stack-based virtual machine struct
@@ -44,6 +402,190 @@ pub struct VM {
     pub program: Vec<Instruction>,
     pub running: bool,
     pub trace: bool,  
+    pub scrub_frames: bool, //fill new frames with SCRUB_SENTINEL instead of 0
+    pub peak_stack: usize, //highest stack height seen while running
+    pub max_stack: usize,  //pushing past this many cells is a StackOverflow
+    pub buffering: OutputBuffering,
+    pub clock: Clock,
+    pub functions: Vec<(String, usize)>, //function names and entry pcs, sorted by pc
+    pub heap: Vec<i64>,
+    pub bytes: Vec<u8>, //the byte segment, starting at BYTES_BASE
+    pub enable_syscalls: bool, //OPEN/READ/CLOS touch the filesystem instead of returning stub values
+    heap_alloc: Allocator,
+    bytes_alloc: Allocator,
+    files: HashMap<i64, File>, //files opened by OPEN, by fd
+    next_fd: i64,
+    output: Box<dyn Write>,
+}
+
+///first-fit bookkeeping for one MALC segment, in offsets from the segment's base
+///kept in a VmSnapshot so a restored VM hands out the same addresses
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Allocator {
+    allocations: HashMap<usize, usize>, //live regions: offset -> size
+    free_regions: Vec<(usize, usize)>,  //freed (offset, size) regions MALC can reuse
+}
+
+impl Allocator {
+    //picks the first freed region that fits, otherwise grows mem by size cells at its end
+    //returns None when growing would pass cap or the host cannot allocate the memory
+    fn alloc<T: Clone + Default>(&mut self, size: usize, mem: &mut Vec<T>, cap: usize) -> Option<usize> {
+        let offset = match self.free_regions.iter().position(|&(_, free)| free >= size) {
+            Some(i) => {
+                let (offset, free) = self.free_regions.remove(i);
+                if free > size {
+                    self.free_regions.push((offset + size, free - size));
+                }
+                offset
+            }
+            None => {
+                let offset = mem.len();
+                let end = offset.checked_add(size).filter(|&end| end <= cap)?;
+                mem.try_reserve(size).ok()?;
+                mem.resize(end, T::default());
+                offset
+            }
+        };
+        self.allocations.insert(offset, size);
+        Some(offset)
+    }
+
+    //returns false when offset is not the start of a live region
+    fn release(&mut self, offset: usize) -> bool {
+        match self.allocations.remove(&offset) {
+            Some(size) => {
+                self.free_regions.push((offset, size));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allocations.is_empty() && self.free_regions.is_empty()
+    }
+
+    //live regions sorted by offset, so the text form is stable
+    fn live_regions(&self) -> Vec<(usize, usize)> {
+        let mut live: Vec<(usize, usize)> = self.allocations.iter().map(|(&o, &s)| (o, s)).collect();
+        live.sort();
+        live
+    }
+}
+
+//"offset:size" pairs joined with ','
+fn format_regions(regions: &[(usize, usize)]) -> String {
+    let pairs: Vec<String> = regions.iter().map(|(o, s)| format!("{}:{}", o, s)).collect();
+    pairs.join(",")
+}
+
+fn parse_regions(value: &str) -> Option<Vec<(usize, usize)>> {
+    value
+        .split(',')
+        .filter(|r| !r.is_empty())
+        .map(|r| {
+            let (o, s) = r.split_once(':')?;
+            Some((o.parse().ok()?, s.parse().ok()?))
+        })
+        .collect()
+}
+
+///saved copy of the VM's mutable state, used to pause and resume a program
+///serializes to a single line like "pc=3 bp=1 running=1 stack=0,5,7"
+///followed by the heap, the byte segment (as hex) and both allocators when they are in use:
+///"heap=1,2 heap_live=0:2 heap_free= bytes=6869 bytes_live=0:2 bytes_free="
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VmSnapshot {
+    pub stack: Vec<i64>,
+    pub pc: usize,
+    pub bp: usize,
+    pub running: bool,
+    pub heap: Vec<i64>,
+    pub bytes: Vec<u8>,
+    pub heap_alloc: Allocator,
+    pub bytes_alloc: Allocator,
+}
+
+impl fmt::Display for VmSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stack: Vec<String> = self.stack.iter().map(|v| v.to_string()).collect();
+        write!(
+            f,
+            "pc={} bp={} running={} stack={}",
+            self.pc,
+            self.bp,
+            self.running as u8,
+            stack.join(",")
+        )?;
+        if !self.heap.is_empty() || !self.heap_alloc.is_empty() {
+            let heap: Vec<String> = self.heap.iter().map(|v| v.to_string()).collect();
+            write!(
+                f,
+                " heap={} heap_live={} heap_free={}",
+                heap.join(","),
+                format_regions(&self.heap_alloc.live_regions()),
+                format_regions(&self.heap_alloc.free_regions)
+            )?;
+        }
+        if !self.bytes.is_empty() || !self.bytes_alloc.is_empty() {
+            let bytes: String = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            write!(
+                f,
+                " bytes={} bytes_live={} bytes_free={}",
+                bytes,
+                format_regions(&self.bytes_alloc.live_regions()),
+                format_regions(&self.bytes_alloc.free_regions)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VmSnapshot {
+    type Err = String;
+
+    //parse the format written by Display back into a snapshot
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut snap = VmSnapshot { running: true, ..VmSnapshot::default() };
+        for field in s.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("malformed snapshot field: {}", field))?;
+            let bad = || format!("bad value for {}: {}", key, value);
+            match key {
+                "pc" => snap.pc = value.parse().map_err(|_| bad())?,
+                "bp" => snap.bp = value.parse().map_err(|_| bad())?,
+                "running" => snap.running = value.parse::<u8>().map_err(|_| bad())? != 0,
+                "stack" => {
+                    for cell in value.split(',').filter(|c| !c.is_empty()) {
+                        snap.stack.push(cell.parse().map_err(|_| bad())?);
+                    }
+                }
+                "heap" => {
+                    for cell in value.split(',').filter(|c| !c.is_empty()) {
+                        snap.heap.push(cell.parse().map_err(|_| bad())?);
+                    }
+                }
+                "bytes" => {
+                    for i in (0..value.len()).step_by(2) {
+                        let byte = value.get(i..i + 2).ok_or_else(bad)?;
+                        snap.bytes.push(u8::from_str_radix(byte, 16).map_err(|_| bad())?);
+                    }
+                }
+                "heap_live" | "bytes_live" | "heap_free" | "bytes_free" => {
+                    let regions = parse_regions(value).ok_or_else(bad)?;
+                    let alloc = if key.starts_with("heap") { &mut snap.heap_alloc } else { &mut snap.bytes_alloc };
+                    if key.ends_with("live") {
+                        alloc.allocations = regions.into_iter().collect();
+                    } else {
+                        alloc.free_regions = regions;
+                    }
+                }
+                other => return Err(format!("unknown snapshot field: {}", other)),
+            }
+        }
+        Ok(snap)
+    }
 }
 
 ///execute the instructions in the program
@@ -57,6 +599,89 @@ impl VM {
             program,
             running: true,
             trace: false,
+            scrub_frames: false,
+            peak_stack: 0,
+            max_stack: DEFAULT_MAX_STACK,
+            buffering: OutputBuffering::Line,
+            clock: Clock::Fixed(0),
+            functions: Vec::new(),
+            heap: Vec::new(),
+            bytes: Vec::new(),
+            enable_syscalls: false,
+            heap_alloc: Allocator::default(),
+            bytes_alloc: Allocator::default(),
+            files: HashMap::new(),
+            next_fd: FIRST_FD,
+            output: Box::new(BufWriter::new(io::stdout())),
+        }
+    }
+
+    //redirect program output (printf) to the given writer
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn set_buffering(&mut self, buffering: OutputBuffering) {
+        self.buffering = buffering;
+    }
+
+    //limits above HEAP_BASE are clamped, a deeper stack would overlap the heap
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.max_stack = max_stack.min(HEAP_BASE);
+    }
+
+    //max_stack is public, so it is clamped again wherever it is checked
+    fn stack_limit(&self) -> usize {
+        self.max_stack.min(HEAP_BASE)
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    //name the functions of the program (from codegen::generate_program) for backtraces
+    pub fn set_functions(&mut self, functions: Vec<(String, usize)>) {
+        self.functions = functions;
+    }
+
+    //the active call frames, innermost first, as (function name, pc) pairs
+    //follows the saved bp chain: each frame keeps the return address at bp-2
+    //and the caller's bp at bp-1, and the call itself is the JSR just before the return address
+    pub fn backtrace(&self) -> Vec<(String, usize)> {
+        let mut frames = vec![(self.function_at(self.pc), self.pc)];
+        let mut bp = self.bp;
+        while bp >= 2 && bp <= self.stack.len() {
+            let ret = self.stack[bp - 2] as usize;
+            let call = ret.saturating_sub(1);
+            frames.push((self.function_at(call), call));
+            bp = self.stack[bp - 1] as usize;
+        }
+        frames
+    }
+
+    //name of the function whose code contains pc
+    fn function_at(&self, pc: usize) -> String {
+        self.functions
+            .iter()
+            .rev()
+            .find(|(_, entry)| *entry <= pc)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| "??".to_string())
+    }
+
+    //run the program while collecting its printf output
+    //returns the value left on top of the stack and everything the program printed,
+    //a VM error still hands back the output written up to that point
+    pub fn run_capturing(&mut self) -> Result<(Option<i64>, String), CapturedError> {
+        let captured = Rc::new(RefCell::new(Vec::new()));
+        let previous = std::mem::replace(&mut self.output, Box::new(SharedBuffer(captured.clone())));
+        let result = self.run();
+        self.output = previous;
+
+        let output = String::from_utf8_lossy(&captured.borrow()).into_owned();
+        match result {
+            Ok(_) => Ok((self.stack.last().copied(), output)),
+            Err(error) => Err(CapturedError { error, output }),
         }
     }
 
@@ -64,200 +689,535 @@ impl VM {
         self.trace = true;
     }
 
-    //run the VM, executing instructions until the program counter exceeds the program length
-    pub fn run(&mut self) {
-        while self.running {
-            if self.trace {
-                eprintln!("TRACE pc={} instr={:?} stack={:?}", self.pc, self.program[self.pc], self.stack);
+    //make reads of uninitialized locals return SCRUB_SENTINEL
+    pub fn enable_frame_scrubbing(&mut self) {
+        self.scrub_frames = true;
+    }
+
+    //capture the current execution state so it can be resumed later
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            stack: self.stack.clone(),
+            pc: self.pc,
+            bp: self.bp,
+            running: self.running,
+            heap: self.heap.clone(),
+            bytes: self.bytes.clone(),
+            heap_alloc: self.heap_alloc.clone(),
+            bytes_alloc: self.bytes_alloc.clone(),
+        }
+    }
+
+    //return to a clean state so the loaded program can be run again
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.pc = 0;
+        self.bp = 0;
+        self.running = true;
+        self.peak_stack = 0;
+        self.heap.clear();
+        self.bytes.clear();
+        self.heap_alloc = Allocator::default();
+        self.bytes_alloc = Allocator::default();
+        self.files.clear();
+        self.next_fd = FIRST_FD;
+    }
+
+    //replace the current execution state with a previously taken snapshot
+    pub fn restore(&mut self, snap: VmSnapshot) {
+        self.stack = snap.stack;
+        self.pc = snap.pc;
+        self.bp = snap.bp;
+        self.running = snap.running;
+        self.heap = snap.heap;
+        self.bytes = snap.bytes;
+        self.heap_alloc = snap.heap_alloc;
+        self.bytes_alloc = snap.bytes_alloc;
+    }
+
+    //push onto the stack, failing once it would grow past max_stack
+    fn push(&mut self, val: i64) -> Result<(), VmError> {
+        if self.stack.len() >= self.stack_limit() {
+            return Err(VmError::StackOverflow);
+        }
+        self.stack.push(val);
+        Ok(())
+    }
+
+    //pop the top of the stack, failing if it is empty
+    fn pop(&mut self) -> Result<i64, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow)
+    }
+
+    //pop the top of the stack and interpret it as a data address
+    fn pop_data_addr(&mut self) -> Result<DataAddr, VmError> {
+        Ok(DataAddr(self.pop()? as usize))
+    }
+
+    //address of the frame slot at the given offset from bp
+    fn frame_addr(&self, offset: isize) -> DataAddr {
+        DataAddr((self.bp as isize + offset) as usize)
+    }
+
+    //read the memory cell at the given data address
+    fn load(&self, addr: DataAddr) -> Result<i64, VmError> {
+        let cell = if addr.0 >= BYTES_BASE {
+            None //words never live in the byte segment
+        } else if addr.0 >= HEAP_BASE {
+            self.heap.get(addr.0 - HEAP_BASE)
+        } else {
+            self.stack.get(addr.0)
+        };
+        cell.copied().ok_or(VmError::BadAddress(addr.0))
+    }
+
+    //write a value into the memory cell at the given data address
+    fn store(&mut self, addr: DataAddr, val: i64) -> Result<(), VmError> {
+        let cell = if addr.0 >= BYTES_BASE {
+            None
+        } else if addr.0 >= HEAP_BASE {
+            self.heap.get_mut(addr.0 - HEAP_BASE)
+        } else {
+            self.stack.get_mut(addr.0)
+        };
+        *cell.ok_or(VmError::BadAddress(addr.0))? = val;
+        Ok(())
+    }
+
+    //read one byte: a real byte in the byte segment, otherwise the low byte of a word cell
+    fn load_byte(&self, addr: DataAddr) -> Result<i64, VmError> {
+        if addr.0 >= BYTES_BASE {
+            let byte = self.bytes.get(addr.0 - BYTES_BASE).ok_or(VmError::BadAddress(addr.0))?;
+            Ok(*byte as i64)
+        } else {
+            Ok(self.load(addr)? & 0xFF)
+        }
+    }
+
+    //write one byte, replacing a whole word cell outside the byte segment
+    fn store_byte(&mut self, addr: DataAddr, val: i64) -> Result<(), VmError> {
+        if addr.0 >= BYTES_BASE {
+            let byte = self.bytes.get_mut(addr.0 - BYTES_BASE).ok_or(VmError::BadAddress(addr.0))?;
+            *byte = val as u8;
+            Ok(())
+        } else {
+            self.store(addr, val & 0xFF)
+        }
+    }
+
+    //read a NUL-terminated string starting at addr, one byte at a time
+    fn load_str(&self, addr: DataAddr) -> Result<String, VmError> {
+        let mut s = Vec::new();
+        loop {
+            match self.load_byte(DataAddr(addr.0 + s.len()))? {
+                0 => return Ok(String::from_utf8_lossy(&s).into_owned()),
+                b => s.push(b as u8),
             }
-            if self.pc >= self.program.len() {
-                panic!("Program counter out of bounds");
+        }
+    }
+
+    //open the file at the path stored at addr, -1 when it cannot be opened
+    fn sys_open(&mut self, addr: DataAddr) -> Result<i64, VmError> {
+        let path = self.load_str(addr)?;
+        match File::open(path) {
+            Ok(file) => {
+                let fd = self.next_fd;
+                self.next_fd += 1;
+                self.files.insert(fd, file);
+                Ok(fd)
             }
+            Err(_) => Ok(-1),
+        }
+    }
 
-            match &self.program[self.pc] {
-                Instruction::IMM(val) => {
-                    self.stack.push(*val);
-                }
-                Instruction::PSH => {
-                    if let Some(&top) = self.stack.last() {
-                        self.stack.push(top);
-                    } else {
-                        panic!("PSH failed: stack is empty");
-                    }
-                }
-                Instruction::ADD => {
-                    let b = self.stack.pop().expect("ADD: missing operand B");
-                    let a = self.stack.pop().expect("ADD: missing operand A");
-                    self.stack.push(a + b);
-                }
-                Instruction::SUB => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a - b);
-                }
-                Instruction::MUL => {
-                    let b = self.stack.pop().expect("MUL: missing operand B");
-                    let a = self.stack.pop().expect("MUL: missing operand A");
-                    self.stack.push(a * b);
-                }
-                Instruction::DIV => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a / b);
-                }
-                Instruction::MOD => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(a % b);
-                }
-                Instruction::JMP(target) => {
-                    self.pc = *target;
-                    continue;
-                }
-                Instruction::BZ(target) => {
-                    let cond = self.stack.pop().unwrap();
-                    if cond == 0 {
-                        self.pc = *target;
-                        continue;
-                    }
-                }
-                Instruction::BNZ(target) => {
-                    let cond = self.stack.pop().unwrap();
-                    if cond != 0 {
-                        self.pc = *target;
-                        continue;
-                    }
-                }
-                Instruction::JSR(target) => {
-                    self.stack.push((self.pc + 1) as i64);
-                    self.pc = *target;
-                    continue;
-                }
-                Instruction::ENT(size) => {
-                    self.stack.push(self.bp as i64);
-                    self.bp = self.stack.len();
-                    self.stack.resize(self.stack.len() + size, 0);
-                }
-                Instruction::ADJ(n) => {
-                    for _ in 0..*n {
-                        self.stack.pop();
-                    }
-                }
-                Instruction::LEV => {
-                    let old_bp = self.stack[self.bp - 1];
-                    self.stack.truncate(self.bp - 1);
-                    self.bp = old_bp as usize;
-                    self.pc = self.stack.pop().unwrap() as usize;
-                    continue;
-                }
-                Instruction::LEA(offset) => {
-                    let addr = self.bp + offset;
-                    self.stack.push(addr as i64);
-                }
-                Instruction::LI => {
-                    let addr = self.stack.pop().unwrap() as usize;
-                    let val = self.stack[addr];
-                    self.stack.push(val);
-                }
-                Instruction::LC => {
-                    let addr = self.stack.pop().unwrap() as usize;
-                    let val = self.stack[addr] & 0xFF;
-                    self.stack.push(val);
-                }
-                Instruction::SI => {
-                    let val = self.stack.pop().unwrap();
-                    let addr = self.stack.pop().unwrap() as usize;
-                    self.stack[addr] = val;
-                }
-                Instruction::SC => {
-                    let val = self.stack.pop().unwrap() & 0xFF;
-                    let addr = self.stack.pop().unwrap() as usize;
-                    self.stack[addr] = val;
-                }
-                Instruction::EXIT => {
-                    //drop the initial dummy value from ENT(0)
-                    //drop dummy only if we actually reserved locals (ENT)
-                    //drop the initial dummy only when the program really began with ENT(...)
-                    if let Some(first) = self.program.get(0) {
-                        if let Instruction::ENT(_) = *first {
-                            if !self.stack.is_empty() {
-                                self.stack.remove(0);
-                                self.stack.remove(0);
-                            }
-                        }
-                    }
+    //read up to count bytes from fd into buf, returns the count read or -1
+    fn sys_read(&mut self, fd: i64, buf: DataAddr, count: i64) -> Result<i64, VmError> {
+        let Some(file) = self.files.get_mut(&fd) else {
+            return Ok(-1);
+        };
+        //never read more than fits between buf and the end of its segment
+        let room = if buf.0 >= BYTES_BASE {
+            self.bytes.len().saturating_sub(buf.0 - BYTES_BASE)
+        } else if buf.0 >= HEAP_BASE {
+            self.heap.len().saturating_sub(buf.0 - HEAP_BASE)
+        } else {
+            self.stack.len().saturating_sub(buf.0)
+        };
+        let wanted = count.max(0) as u64;
+        if wanted > 0 && room == 0 {
+            return Err(VmError::BadAddress(buf.0));
+        }
+        let mut data = vec![0; wanted.min(room as u64) as usize];
+        let n = match file.read(&mut data) {
+            Ok(n) => n,
+            Err(_) => return Ok(-1),
+        };
+        for (i, &b) in data[..n].iter().enumerate() {
+            self.store_byte(DataAddr(buf.0 + i), b as i64)?;
+        }
+        Ok(n as i64)
+    }
 
-                     //println!("Final stack: {:?}", self.stack);
-                     if let Some(&result) = self.stack.last() {
-                         println!("Program exited with value: {}", result);
-                     } else {
-                         println!("Program exited: stack is empty");
-                     }
-                     self.running = false;
-                 }
+    //reserve size heap cells, or bytes when asked for MALC_BYTES
+    //None when the segment has no room left for size more cells
+    fn malloc(&mut self, size: usize, flags: i64) -> Option<usize> {
+        if flags & MALC_BYTES != 0 {
+            let offset = self.bytes_alloc.alloc(size, &mut self.bytes, MAX_BYTES)?;
+            Some(BYTES_BASE + offset)
+        } else {
+            let offset = self.heap_alloc.alloc(size, &mut self.heap, MAX_HEAP_CELLS)?;
+            Some(HEAP_BASE + offset)
+        }
+    }
 
+    //give a region returned by malloc back to the allocator of its segment
+    fn free(&mut self, ptr: usize) -> Result<(), VmError> {
+        let freed = if ptr >= BYTES_BASE {
+            self.bytes_alloc.release(ptr - BYTES_BASE)
+        } else if ptr >= HEAP_BASE {
+            self.heap_alloc.release(ptr - HEAP_BASE)
+        } else {
+            false
+        };
+        if freed { Ok(()) } else { Err(VmError::BadAddress(ptr)) }
+    }
 
+    //write program output, flushing according to the buffering mode
+    fn write_output(&mut self, s: &str) -> Result<(), VmError> {
+        self.output.write_all(s.as_bytes()).map_err(|e| VmError::Output(e.kind()))?;
+        let flush = match self.buffering {
+            OutputBuffering::Line => s.contains('\n'),
+            OutputBuffering::Full => false,
+            OutputBuffering::None => true,
+        };
+        if flush {
+            self.output.flush().map_err(|e| VmError::Output(e.kind()))?;
+        }
+        Ok(())
+    }
 
-                Instruction::PrintfStr(s) => {
-                    print!("{}", s);
+    //run the program and panic on a VM error, like the VM did before run returned a Result
+    pub fn run_or_panic(&mut self) -> i64 {
+        match self.run() {
+            Ok(value) => value,
+            Err(e) => {
+                eprintln!("backtrace:");
+                for (name, pc) in self.backtrace() {
+                    eprintln!("    at {} (pc {})", name, pc);
                 }
-                Instruction::MALC => {
-                    //MALC takes two inputs (size, flags) pop them both
-                    let _flags = self.stack.pop().expect("MALC missing flags");
-                    let _size  = self.stack.pop().expect("MALC missing size");
-                    //push an error/status code of 0, then the pointer
-                    self.stack.push(0);
-                    self.stack.push(0x1000);
+                panic!("VM error at pc={}: {}", self.pc, e)
+            }
+        }
+    }
 
+    //run the VM until the program stops
+    //returns the exit value (the top of the stack, or 0 if it is empty)
+    //on error, pc is left pointing at the failing instruction
+    pub fn run(&mut self) -> Result<i64, VmError> {
+        while self.step()? {}
+        Ok(self.stack.last().copied().unwrap_or(0))
+    }
+
+    ///like run, but gives up with BudgetExceeded after max_steps instructions
+    ///so untrusted programs cannot loop forever
+    pub fn run_with_budget(&mut self, max_steps: usize) -> Result<i64, VmError> {
+        let mut steps = 0;
+        while self.running {
+            if steps == max_steps {
+                return Err(VmError::BudgetExceeded);
+            }
+            self.step()?;
+            steps += 1;
+        }
+        Ok(self.stack.last().copied().unwrap_or(0))
+    }
+
+    ///executes exactly one instruction, returning whether the VM is still running
+    ///on an error pc is left at the instruction that failed
+    pub fn step(&mut self) -> Result<bool, VmError> {
+        if !self.running {
+            return Ok(false);
+        }
+        self.peak_stack = self.peak_stack.max(self.stack.len());
+        if self.pc >= self.program.len() {
+            return Err(VmError::PcOutOfBounds);
+        }
+        if self.trace {
+            eprintln!("TRACE pc={} instr={:?} stack={:?}", self.pc, self.program[self.pc], self.stack);
+        }
+
+        match &self.program[self.pc] {
+            Instruction::IMM(val) => {
+                self.push(*val)?;
+            }
+            Instruction::PSH => {
+                let top = *self.stack.last().ok_or(VmError::StackUnderflow)?;
+                self.push(top)?;
+            }
+            Instruction::POP => {
+                self.pop()?;
+            }
+            Instruction::ADD => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a + b)?;
+            }
+            Instruction::SUB => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a - b)?;
+            }
+            Instruction::MUL => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a * b)?;
+            }
+            Instruction::DIV => {
+                //pc still points at the DIV when the error is returned
+                let b = self.pop()?;
+                let a = self.pop()?;
+                if b == 0 {
+                    return Err(VmError::DivByZero);
                 }
-                Instruction::FREE => {
-                    let _ = self.stack.pop();
-                }
-                Instruction::MSET => {
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
+                self.push(a.wrapping_div(b))?;
+            }
+            Instruction::MOD => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                if b == 0 {
+                    return Err(VmError::DivByZero);
                 }
-                Instruction::MCMP => {
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
-                    self.stack.push(0);
+                self.push(a.wrapping_rem(b))?;
+            }
+            Instruction::JMP(target) => {
+                self.pc = target.0;
+                return Ok(true);
+            }
+            Instruction::BZ(target) => {
+                let target = *target;
+                if self.pop()? == 0 {
+                    self.pc = target.0;
+                    return Ok(true);
                 }
-                Instruction::OPEN => {
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
-                    self.stack.push(3);
+            }
+            Instruction::BNZ(target) => {
+                let target = *target;
+                if self.pop()? != 0 {
+                    self.pc = target.0;
+                    return Ok(true);
                 }
-                Instruction::READ => {
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
-                    let _ = self.stack.pop();
-                    self.stack.push(10);
+            }
+            Instruction::JSR(target) => {
+                let target = *target;
+                self.push((self.pc + 1) as i64)?;
+                self.pc = target.0;
+                return Ok(true);
+            }
+            Instruction::ENT(size) => {
+                let size = *size;
+                //check the whole frame up front, before resize allocates it
+                if self.stack.len().saturating_add(1).saturating_add(size) > self.stack_limit() {
+                    return Err(VmError::StackOverflow);
                 }
-                Instruction::CLOS => {
-                    let _ = self.stack.pop();
-                    self.stack.push(0);
+                self.push(self.bp as i64)?;
+                self.bp = self.stack.len();
+                let fill = if self.scrub_frames { SCRUB_SENTINEL } else { 0 };
+                self.stack.resize(self.stack.len() + size, fill);
+            }
+            Instruction::ADJ(n) => {
+                //drop the call arguments sitting under the callee's return value
+                let n = *n;
+                let result = self.pop()?;
+                if self.stack.len() < n {
+                    return Err(VmError::StackUnderflow);
                 }
-                Instruction::EQ => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push((a == b) as i64);
+                self.stack.truncate(self.stack.len() - n);
+                self.push(result)?;
+            }
+            Instruction::LEV => {
+                //bp == 0 means no ENT ever set up a frame, so there is nothing to leave
+                if self.bp == 0 {
+                    return Err(VmError::StackUnderflow);
                 }
-                Instruction::LT => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push((a < b) as i64);
+                //codegen leaves the return value on top; a bare frame returns nothing
+                let result = if self.stack.len() > self.bp { self.stack.pop() } else { None };
+                let old_bp = self.load(DataAddr(self.bp - 1))?;
+                self.stack.truncate(self.bp - 1);
+                self.bp = old_bp as usize;
+                let ret = CodeAddr(self.pop()? as usize);
+                self.stack.extend(result);
+                self.pc = ret.0;
+                return Ok(true);
+            }
+            Instruction::LEA(offset) => {
+                let addr = self.frame_addr(*offset);
+                self.push(addr.0 as i64)?;
+            }
+            Instruction::LOAD(offset) => {
+                let val = self.load(self.frame_addr(*offset))?;
+                self.push(val)?;
+            }
+            Instruction::STORE(offset) => {
+                let addr = self.frame_addr(*offset);
+                let val = self.pop()?;
+                self.store(addr, val)?;
+            }
+            Instruction::LI => {
+                let addr = self.pop_data_addr()?;
+                let val = self.load(addr)?;
+                self.push(val)?;
+            }
+            Instruction::LC => {
+                let addr = self.pop_data_addr()?;
+                let val = self.load_byte(addr)?;
+                self.push(val)?;
+            }
+            Instruction::SI => {
+                let val = self.pop()?;
+                let addr = self.pop_data_addr()?;
+                self.store(addr, val)?;
+            }
+            Instruction::SC => {
+                let val = self.pop()?;
+                let addr = self.pop_data_addr()?;
+                self.store_byte(addr, val)?;
+            }
+            Instruction::EXIT => {
+                //the exit value is whatever codegen left on top, the rest of the stack is untouched
+                //make sure buffered program output is written before the caller reports the exit
+                self.output.flush().map_err(|e| VmError::Output(e.kind()))?;
+                self.running = false;
+            }
+
+
+
+            Instruction::PrintfStr(fmt) => {
+                let s = format_printf(fmt, &[]);
+                self.write_output(&s)?;
+            }
+            Instruction::PrintfFmt(fmt, n) => {
+                let n = *n;
+                if self.stack.len() < n {
+                    return Err(VmError::StackUnderflow);
                 }
-                Instruction::GT => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push((a > b) as i64);
+                //the first argument was pushed first, so it sits deepest
+                let args = self.stack.split_off(self.stack.len() - n);
+                let s = format_printf(fmt, &args);
+                self.write_output(&s)?;
+            }
+            Instruction::MALC => {
+                //MALC takes two inputs (size, flags) pop them both
+                let flags = self.pop()?;
+                let size  = self.pop()?;
+                //push a status of 0 and the pointer, or -1 and a null pointer when it does not fit
+                match self.malloc(size.max(0) as usize, flags) {
+                    Some(ptr) => {
+                        self.push(0)?;
+                        self.push(ptr as i64)?;
+                    }
+                    None => {
+                        self.push(-1)?;
+                        self.push(0)?;
+                    }
                 }
             }
-
-            self.pc += 1;
+            Instruction::FREE => {
+                let ptr = self.pop()?;
+                self.free(ptr as usize)?;
+            }
+            Instruction::MSET => {
+                let _ = self.stack.pop();
+                let _ = self.stack.pop();
+                let _ = self.stack.pop();
+            }
+            Instruction::MCMP => {
+                let _ = self.stack.pop();
+                let _ = self.stack.pop();
+                let _ = self.stack.pop();
+                self.push(0)?;
+            }
+            //without enable_syscalls these push fixed values so tests stay deterministic
+            Instruction::OPEN => {
+                let _flags = self.pop()?; //files are only ever opened for reading
+                let path = self.pop_data_addr()?;
+                let fd = if self.enable_syscalls { self.sys_open(path)? } else { 3 };
+                self.push(fd)?;
+            }
+            Instruction::READ => {
+                let count = self.pop()?;
+                let buf = self.pop_data_addr()?;
+                let fd = self.pop()?;
+                let n = if self.enable_syscalls { self.sys_read(fd, buf, count)? } else { 10 };
+                self.push(n)?;
+            }
+            Instruction::CLOS => {
+                let fd = self.pop()?;
+                let status = if !self.enable_syscalls || self.files.remove(&fd).is_some() { 0 } else { -1 };
+                self.push(status)?;
+            }
+            Instruction::EQ => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push((a == b) as i64)?;
+            }
+            Instruction::LT => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push((a < b) as i64)?;
+            }
+            Instruction::GT => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push((a > b) as i64)?;
+            }
+            Instruction::NE => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push((a != b) as i64)?;
+            }
+            Instruction::LE => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push((a <= b) as i64)?;
+            }
+            Instruction::GE => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push((a >= b) as i64)?;
+            }
+            Instruction::NEG => {
+                //wrapping so that -i64::MIN stays i64::MIN instead of panicking
+                let a = self.pop()?;
+                self.push(a.wrapping_neg())?;
+            }
+            Instruction::BNOT => {
+                let a = self.pop()?;
+                self.push(!a)?;
+            }
+            Instruction::AND | Instruction::OR | Instruction::XOR | Instruction::SHL
+            | Instruction::SHR => {
+                let op = self.program[self.pc].clone();
+                let b = self.pop()?;
+                let a = self.pop()?;
+                //shift counts wrap modulo 64 instead of panicking
+                let result = match op {
+                    Instruction::AND => a & b,
+                    Instruction::OR => a | b,
+                    Instruction::XOR => a ^ b,
+                    Instruction::SHL => a.wrapping_shl(b as u32),
+                    _ => a.wrapping_shr(b as u32),
+                };
+                self.push(result)?;
+            }
+            Instruction::TIME => {
+                self.push(self.clock.now())?;
+            }
+            Instruction::NOP => {}
         }
+
+        self.pc += 1;
+
+        Ok(self.running)
     }
 }
 
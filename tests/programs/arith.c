@@ -0,0 +1,10 @@
+int square(int n) { return n * n; }
+
+int main()
+{
+  int a = 17;
+  int b = 5;
+  printf("%d %d %d\n", a + b, a / b, a % b);
+  printf("%d\n", square(a - b));
+  return (a - b) * 2;
+}
@@ -0,0 +1,5 @@
+int main()
+{
+  printf("hello, world\n");
+  return 0;
+}